@@ -0,0 +1,239 @@
+use log::debug;
+use std::collections::HashMap;
+use std::fmt;
+use std::fs::File;
+use std::io::BufRead;
+use std::path::{Path, PathBuf};
+
+/// Mount point of cgroup hierarchies
+const CGROUP_MOUNT: &str = "/sys/fs/cgroup";
+
+/// Cgroup membership of the current process
+const PROC_SELF_CGROUP: &str = "/proc/self/cgroup";
+
+/// Values at least that large in cgroups v1 limit files mean no limit
+const CGROUPS_V1_NO_LIMIT: u64 = 0x7FFFFFFFFFFF0000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CgroupVersion {
+    V1,
+    V2,
+}
+
+impl fmt::Display for CgroupVersion {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CgroupVersion::V1 => write!(fmt, "v1"),
+            CgroupVersion::V2 => write!(fmt, "v2"),
+        }
+    }
+}
+
+/// Cgroup of the current process
+#[derive(Debug, Clone)]
+pub struct Cgroup {
+    pub version: CgroupVersion,
+    /// Directory with per-controller hierarchies for v1 or own cgroup directory for v2
+    path: PathBuf,
+}
+
+impl Cgroup {
+    /// Detect cgroup hierarchy of the current process
+    pub fn detect() -> Option<Cgroup> {
+        Self::detect_at(Path::new(CGROUP_MOUNT), Path::new(PROC_SELF_CGROUP))
+    }
+
+    pub(crate) fn detect_at(mount: &Path, proc_cgroup: &Path) -> Option<Cgroup> {
+        if mount.join("cgroup.controllers").exists() {
+            // unified hierarchy, find own cgroup
+            let own_path = std::fs::read_to_string(proc_cgroup)
+                .ok()
+                .and_then(|content| parse_unified_path(&content).map(|s| s.to_string()))
+                .unwrap_or_default();
+            let mut path = mount.join(own_path.trim_start_matches('/'));
+            // without cgroup namespace the own cgroup can be outside of the mounted tree
+            if own_path.contains("..") || !path.join("cgroup.controllers").exists() {
+                debug!("cgroups v2 path {} is not accessible", own_path);
+                path = mount.to_path_buf();
+            }
+            return Some(Cgroup {
+                version: CgroupVersion::V2,
+                path,
+            });
+        }
+        if mount.join("memory").is_dir() || mount.join("cpu").is_dir() {
+            return Some(Cgroup {
+                version: CgroupVersion::V1,
+                path: mount.to_path_buf(),
+            });
+        }
+        None
+    }
+
+    /// Path to a file of the controller
+    pub fn file(&self, controller: &str, name: &str) -> PathBuf {
+        match self.version {
+            CgroupVersion::V1 => self.path.join(controller).join(name),
+            CgroupVersion::V2 => self.path.join(name),
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+/// Find path of the unified hierarchy in /proc/self/cgroup content
+///
+/// Line format is:
+/// 0::/system.slice/docker-1234.scope
+fn parse_unified_path(content: &str) -> Option<&str> {
+    content
+        .lines()
+        .find_map(|line| line.strip_prefix("0::"))
+        .map(|path| path.trim())
+}
+
+/// Read a file with a single numeric value
+pub fn read_value(path: &Path) -> Result<u64, Box<dyn std::error::Error>> {
+    let err = std::io::Error::from(std::io::ErrorKind::NotFound);
+    let file = File::open(path)?;
+    Ok(std::io::BufReader::new(file)
+        .lines()
+        .next()
+        .ok_or_else(|| Box::new(err))??
+        .trim()
+        .parse::<u64>()?)
+}
+
+/// Read a file with a limit value, returns None if no limit is imposed
+///
+/// Cgroups v2 uses the `max` word while cgroups v1 uses a huge value
+pub fn read_limit(path: &Path) -> Result<Option<u64>, Box<dyn std::error::Error>> {
+    let content = std::fs::read_to_string(path)?;
+    let value = content.trim();
+    if value == "max" {
+        return Ok(None);
+    }
+    let value = value.parse::<u64>()?;
+    if value >= CGROUPS_V1_NO_LIMIT {
+        return Ok(None);
+    }
+    Ok(Some(value))
+}
+
+/// Read a flat keyed file like memory.stat or cpu.stat
+pub fn read_keyed(path: &Path) -> Result<HashMap<String, u64>, Box<dyn std::error::Error>> {
+    Ok(parse_keyed(&std::fs::read_to_string(path)?))
+}
+
+/// Parse a flat keyed content with lines like `key 12345`
+pub fn parse_keyed(content: &str) -> HashMap<String, u64> {
+    content
+        .lines()
+        .filter_map(|line| {
+            let mut parts = line.split_whitespace();
+            let key = parts.next()?;
+            let value = parts.next()?.parse::<u64>().ok()?;
+            Some((key.to_string(), value))
+        })
+        .collect()
+}
+
+/// Directory with files for tests, removed on drop
+#[cfg(test)]
+pub(crate) struct Fixture(PathBuf);
+
+#[cfg(test)]
+impl std::ops::Deref for Fixture {
+    type Target = Path;
+
+    fn deref(&self) -> &Path {
+        &self.0
+    }
+}
+
+#[cfg(test)]
+impl Drop for Fixture {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
+
+/// Create a directory with files for tests
+#[cfg(test)]
+pub(crate) fn create_fixture(name: &str, files: &[(&str, &str)]) -> Fixture {
+    let root = std::env::temp_dir().join(format!(
+        "cloudwatch_metrics_agent-{}-{}",
+        name,
+        std::process::id()
+    ));
+    let _ = std::fs::remove_dir_all(&root);
+    for (file, content) in files {
+        let path = root.join(file);
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(path, content).unwrap();
+    }
+    Fixture(root)
+}
+
+/// Tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_unified_path() {
+        let content = "12:pids:/docker/abc\n0::/system.slice/docker-abc.scope\n";
+        assert_eq!(
+            parse_unified_path(content),
+            Some("/system.slice/docker-abc.scope")
+        );
+        assert_eq!(parse_unified_path("4:memory:/docker/abc\n"), None);
+    }
+
+    #[test]
+    fn test_parse_keyed() {
+        let stat = parse_keyed("anon 1024\nfile 2048\nbroken\n");
+        assert_eq!(stat.len(), 2);
+        assert_eq!(stat["anon"], 1024);
+        assert_eq!(stat["file"], 2048);
+    }
+
+    #[test]
+    fn test_detect_v2() {
+        let root = create_fixture(
+            "detect-v2",
+            &[
+                ("proc/cgroup", "0::/ecs/task\n"),
+                ("mnt/cgroup.controllers", "cpu memory\n"),
+                ("mnt/ecs/task/cgroup.controllers", "cpu memory\n"),
+                ("mnt/ecs/task/memory.max", "max\n"),
+            ],
+        );
+        let cgroup = Cgroup::detect_at(&root.join("mnt"), &root.join("proc/cgroup")).unwrap();
+        assert_eq!(cgroup.version, CgroupVersion::V2);
+        assert_eq!(cgroup.path(), root.join("mnt/ecs/task"));
+        assert_eq!(
+            read_limit(&cgroup.file("memory", "memory.max")).unwrap(),
+            None
+        );
+    }
+
+    #[test]
+    fn test_detect_v1() {
+        let root = create_fixture(
+            "detect-v1",
+            &[
+                ("proc/cgroup", "4:memory:/docker/abc\n0::/\n"),
+                ("mnt/memory/memory.usage_in_bytes", "4096\n"),
+            ],
+        );
+        let cgroup = Cgroup::detect_at(&root.join("mnt"), &root.join("proc/cgroup")).unwrap();
+        assert_eq!(cgroup.version, CgroupVersion::V1);
+        assert_eq!(
+            read_value(&cgroup.file("memory", "memory.usage_in_bytes")).unwrap(),
+            4096
+        );
+    }
+}
@@ -1,13 +1,19 @@
 use crate::config::CloudwatchConfig;
+use crate::custom::{CustomMetric, CustomValue};
 use crate::metrics::Measurement;
 use crate::publisher::MetricPublisher;
 
 use async_trait::async_trait;
 use aws_config::meta::region::RegionProviderChain;
 use aws_config::BehaviorVersion;
-use aws_sdk_cloudwatch::types::{Dimension, MetricDatum, StandardUnit};
+use aws_sdk_cloudwatch::types::{Dimension, MetricDatum, StandardUnit, StatisticSet};
 use aws_sdk_cloudwatch::Client;
-use log::info;
+use log::{error, info};
+use std::collections::BTreeMap;
+use std::time::SystemTime;
+
+/// Maximum number of datums in a PutMetricData request
+const MAX_DATUMS_PER_REQUEST: usize = 1000;
 
 /// Sink implementation that sends metrics to Cloudwatch
 pub struct CloudwatchPublisher {
@@ -31,62 +37,618 @@ async fn create_client(_config: &CloudwatchConfig) -> Client {
     Client::new(&shared_config)
 }
 
+impl CloudwatchPublisher {
+    /// Create a datum with the service dimension
+    fn datum(
+        &self,
+        name: &str,
+        value: f64,
+        unit: StandardUnit,
+        timestamp: SystemTime,
+    ) -> MetricDatum {
+        self.datum_with(name, value, unit, timestamp, &[])
+    }
+
+    /// Create a datum with the service dimension and additional dimensions
+    fn datum_with(
+        &self,
+        name: &str,
+        value: f64,
+        unit: StandardUnit,
+        timestamp: SystemTime,
+        dimensions: &[(&str, &str)],
+    ) -> MetricDatum {
+        let mut builder = MetricDatum::builder().dimensions(
+            Dimension::builder()
+                .name("ServiceName")
+                .value(&self.config.service_name)
+                .build(),
+        );
+        for (name, value) in dimensions {
+            builder = builder.dimensions(Dimension::builder().name(*name).value(*value).build());
+        }
+        builder
+            .metric_name(name)
+            .value(value)
+            .timestamp(timestamp.into())
+            .unit(unit)
+            .build()
+    }
+
+    /// Create a datum of a custom metric, with the service dimension
+    /// if it is published to the agent namespace
+    fn custom_datum(&self, metric: &CustomMetric, timestamp: SystemTime) -> MetricDatum {
+        let mut builder = MetricDatum::builder();
+        if metric.key.namespace.is_none() {
+            builder = builder.dimensions(
+                Dimension::builder()
+                    .name("ServiceName")
+                    .value(&self.config.service_name)
+                    .build(),
+            );
+        }
+        for (name, value) in &metric.key.dimensions {
+            builder = builder.dimensions(Dimension::builder().name(name).value(value).build());
+        }
+        builder = match metric.value {
+            CustomValue::Value(value) => builder.value(value),
+            CustomValue::Statistics {
+                min,
+                max,
+                sum,
+                count,
+            } => builder.statistic_values(
+                StatisticSet::builder()
+                    .minimum(min)
+                    .maximum(max)
+                    .sum(sum)
+                    .sample_count(count)
+                    .build(),
+            ),
+        };
+        // unknown units are published without a unit
+        let unit = if StandardUnit::values().contains(&metric.key.unit.as_str()) {
+            StandardUnit::from(metric.key.unit.as_str())
+        } else {
+            StandardUnit::None
+        };
+        builder
+            .metric_name(&metric.key.name)
+            .timestamp(timestamp.into())
+            .unit(unit)
+            .build()
+    }
+
+    /// Send datums to a namespace in requests of the allowed size,
+    /// a failed request is logged and does not stop the rest
+    async fn put_datums(
+        &self,
+        namespace: &str,
+        datums: Vec<MetricDatum>,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let mut result = Ok(());
+        for datums in datums.chunks(MAX_DATUMS_PER_REQUEST) {
+            if let Err(err) = self
+                .client
+                .put_metric_data()
+                .namespace(namespace)
+                .set_metric_data(Some(datums.to_vec()))
+                .send()
+                .await
+            {
+                error!(
+                    "Failed to send {} metrics to {}: {}",
+                    datums.len(),
+                    namespace,
+                    err
+                );
+                result = Err(err.into());
+            }
+        }
+        result
+    }
+
+    /// Convert measurement to a list of datums
+    fn measurement_datums(&self, measurement: &Measurement) -> Vec<MetricDatum> {
+        let ts = measurement.timestamp;
+        let mut datums = vec![
+            self.datum(
+                "CPUUtilization",
+                measurement.cpu_utilization,
+                StandardUnit::Percent,
+                ts,
+            ),
+            self.datum(
+                "MemoryUtilization",
+                measurement.mem_utilization,
+                StandardUnit::Percent,
+                ts,
+            ),
+            self.datum(
+                "MaxMemoryUtilization",
+                measurement.max_mem_utilization,
+                StandardUnit::Percent,
+                ts,
+            ),
+            self.datum(
+                "MemoryUsed",
+                measurement.mem_used as f64,
+                StandardUnit::Bytes,
+                ts,
+            ),
+            self.datum(
+                "MemoryLimit",
+                measurement.mem_limit as f64,
+                StandardUnit::Bytes,
+                ts,
+            ),
+            self.datum(
+                "MaxMemoryUsed",
+                measurement.max_mem_used as f64,
+                StandardUnit::Bytes,
+                ts,
+            ),
+        ];
+        if let Some(breakdown) = &measurement.mem_breakdown {
+            for (name, value) in [
+                ("MemoryAnon", Some(breakdown.anon)),
+                ("MemoryFileCache", Some(breakdown.file)),
+                ("MemoryShmem", Some(breakdown.shmem)),
+                ("MemorySlab", breakdown.slab),
+                ("MemoryWorkingSet", Some(breakdown.working_set)),
+            ] {
+                if let Some(value) = value {
+                    datums.push(self.datum(name, value as f64, StandardUnit::Bytes, ts));
+                }
+            }
+        }
+        if let Some(swap) = &measurement.swap {
+            datums.push(self.datum(
+                "SwapUtilization",
+                swap.utilization(),
+                StandardUnit::Percent,
+                ts,
+            ));
+            datums.push(self.datum(
+                "MaxSwapUtilization",
+                swap.max_utilization(),
+                StandardUnit::Percent,
+                ts,
+            ));
+            datums.push(self.datum("SwapUsed", swap.used as f64, StandardUnit::Bytes, ts));
+            datums.push(self.datum("MaxSwapUsed", swap.max_used as f64, StandardUnit::Bytes, ts));
+        }
+        for core in &measurement.cores {
+            let core_name = core.core.to_string();
+            let dimensions = [("Core", core_name.as_str())];
+            datums.push(self.datum_with(
+                "CPUUtilization",
+                core.utilization,
+                StandardUnit::Percent,
+                ts,
+                &dimensions,
+            ));
+            datums.push(self.datum_with(
+                "MaxCPUUtilization",
+                core.max_utilization,
+                StandardUnit::Percent,
+                ts,
+                &dimensions,
+            ));
+        }
+        if let Some(max_core) = measurement.max_core_utilization() {
+            datums.push(self.datum("MaxCoreUtilization", max_core, StandardUnit::Percent, ts));
+        }
+        if let Some(times) = &measurement.cpu_times {
+            for (name, value) in [
+                ("CPUUser", times.user + times.nice),
+                ("CPUSystem", times.system),
+                ("CPUIOWait", times.iowait),
+                ("CPUIrq", times.irq),
+                ("CPUSoftIrq", times.softirq),
+                ("CPUSteal", times.steal),
+            ] {
+                datums.push(self.datum(name, times.ratio(value), StandardUnit::Percent, ts));
+            }
+        }
+        if let Some(steal_sustained) = measurement.steal_sustained {
+            datums.push(self.datum(
+                "CPUStealSustained",
+                if steal_sustained { 1.0 } else { 0.0 },
+                StandardUnit::None,
+                ts,
+            ));
+        }
+        if let Some(throttling) = &measurement.cpu_throttling {
+            datums.push(self.datum(
+                "CPUThrottledPercent",
+                throttling.throttled_ratio(),
+                StandardUnit::Percent,
+                ts,
+            ));
+            datums.push(self.datum(
+                "CPUThrottledTime",
+                throttling.throttled_usec as f64 / 1e6,
+                StandardUnit::Seconds,
+                ts,
+            ));
+        }
+        if let Some(load) = &measurement.load {
+            for (name, value) in [
+                ("LoadAverage1", load.load1),
+                ("LoadAverage5", load.load5),
+                ("LoadAverage15", load.load15),
+            ] {
+                datums.push(self.datum(name, value, StandardUnit::None, ts));
+            }
+            datums.push(self.datum(
+                "ProcsRunning",
+                load.procs_running as f64,
+                StandardUnit::Count,
+                ts,
+            ));
+            datums.push(self.datum(
+                "ProcsBlocked",
+                load.procs_blocked as f64,
+                StandardUnit::Count,
+                ts,
+            ));
+        }
+        if let Some(pressure) = &measurement.pressure {
+            for (name, values) in pressure.resources() {
+                let Some(values) = values else { continue };
+                datums.push(self.datum(
+                    &format!("{}PressureSome", name),
+                    values.some_avg10,
+                    StandardUnit::Percent,
+                    ts,
+                ));
+                datums.push(self.datum(
+                    &format!("{}StallTimeSome", name),
+                    values.some_total as f64 / 1e6,
+                    StandardUnit::Seconds,
+                    ts,
+                ));
+                if let (Some(full_avg10), Some(full_total)) = (values.full_avg10, values.full_total)
+                {
+                    datums.push(self.datum(
+                        &format!("{}PressureFull", name),
+                        full_avg10,
+                        StandardUnit::Percent,
+                        ts,
+                    ));
+                    datums.push(self.datum(
+                        &format!("{}StallTimeFull", name),
+                        full_total as f64 / 1e6,
+                        StandardUnit::Seconds,
+                        ts,
+                    ));
+                }
+            }
+        }
+        if let Some(events) = &measurement.memory_events {
+            for (name, value) in [
+                ("MemoryHighEvents", events.high),
+                ("MemoryMaxEvents", events.max),
+                ("OOMEvents", events.oom),
+                ("OOMKills", events.oom_kill),
+            ] {
+                if let Some(value) = value {
+                    datums.push(self.datum(name, value as f64, StandardUnit::Count, ts));
+                }
+            }
+        }
+        if let Some(io) = &measurement.io {
+            let counters = &io.counters;
+            for (name, value, unit) in [
+                (
+                    "DiskReadBytes",
+                    counters.read_bytes,
+                    StandardUnit::BytesSecond,
+                ),
+                (
+                    "DiskWriteBytes",
+                    counters.write_bytes,
+                    StandardUnit::BytesSecond,
+                ),
+                ("DiskReadOps", counters.read_ops, StandardUnit::CountSecond),
+                (
+                    "DiskWriteOps",
+                    counters.write_ops,
+                    StandardUnit::CountSecond,
+                ),
+            ] {
+                datums.push(self.datum(name, io.rate(value), unit, ts));
+            }
+        }
+        if let Some(vmstat) = &measurement.vmstat {
+            let counters = &vmstat.counters;
+            for (name, value) in [
+                ("PageFaults", counters.page_faults),
+                ("MajorPageFaults", counters.major_page_faults),
+                ("SwapIns", counters.swap_ins),
+                ("SwapOuts", counters.swap_outs),
+                ("WorkingSetRefaults", counters.workingset_refaults),
+                ("ContextSwitches", counters.context_switches),
+            ] {
+                datums.push(self.datum(name, vmstat.rate(value), StandardUnit::CountSecond, ts));
+            }
+        }
+        if let Some(pids) = &measurement.pids {
+            datums.push(self.datum(
+                "ProcessCount",
+                pids.processes as f64,
+                StandardUnit::Count,
+                ts,
+            ));
+            datums.push(self.datum("ThreadCount", pids.threads as f64, StandardUnit::Count, ts));
+            if let Some(pids_current) = pids.pids_current {
+                datums.push(self.datum(
+                    "PidsCurrent",
+                    pids_current as f64,
+                    StandardUnit::Count,
+                    ts,
+                ));
+            }
+            if let Some(utilization) = pids.utilization() {
+                datums.push(self.datum("PidsUtilization", utilization, StandardUnit::Percent, ts));
+            }
+        }
+        if let Some(files) = &measurement.files {
+            datums.push(self.datum(
+                "SystemOpenFiles",
+                files.allocated as f64,
+                StandardUnit::Count,
+                ts,
+            ));
+            datums.push(self.datum(
+                "SystemFileUtilization",
+                files.utilization(),
+                StandardUnit::Percent,
+                ts,
+            ));
+        }
+        for process in &measurement.processes {
+            let dimensions = [("Process", process.label.as_str())];
+            for (name, value, unit) in [
+                (
+                    "ProcessInstances",
+                    process.instances as f64,
+                    StandardUnit::Count,
+                ),
+                (
+                    "ProcessCPUUtilization",
+                    process.cpu_utilization,
+                    StandardUnit::Percent,
+                ),
+                ("ProcessMemoryRSS", process.rss as f64, StandardUnit::Bytes),
+                (
+                    "ProcessMemoryVirtual",
+                    process.virtual_memory as f64,
+                    StandardUnit::Bytes,
+                ),
+                (
+                    "ProcessThreads",
+                    process.threads as f64,
+                    StandardUnit::Count,
+                ),
+                (
+                    "ProcessOpenFiles",
+                    process.open_files as f64,
+                    StandardUnit::Count,
+                ),
+                (
+                    "ProcessUptime",
+                    process.uptime as f64,
+                    StandardUnit::Seconds,
+                ),
+            ] {
+                datums.push(self.datum_with(name, value, unit, ts, &dimensions));
+            }
+            for (name, value) in [
+                (
+                    "ProcessVoluntaryContextSwitches",
+                    process.voluntary_switches,
+                ),
+                (
+                    "ProcessInvoluntaryContextSwitches",
+                    process.involuntary_switches,
+                ),
+            ] {
+                datums.push(self.datum_with(
+                    name,
+                    process.rate(value),
+                    StandardUnit::CountSecond,
+                    ts,
+                    &dimensions,
+                ));
+            }
+            if let Some(fd_utilization) = process.fd_utilization {
+                datums.push(self.datum_with(
+                    "ProcessFDUtilization",
+                    fd_utilization,
+                    StandardUnit::Percent,
+                    ts,
+                    &dimensions,
+                ));
+            }
+        }
+        if let Some(top) = &measurement.top_processes {
+            for usage in &top.by_cpu {
+                datums.push(self.datum_with(
+                    "TopProcessCPUUtilization",
+                    usage.cpu_utilization,
+                    StandardUnit::Percent,
+                    ts,
+                    &[("ProcessName", usage.name.as_str())],
+                ));
+            }
+            for usage in &top.by_rss {
+                datums.push(self.datum_with(
+                    "TopProcessMemoryRSS",
+                    usage.rss as f64,
+                    StandardUnit::Bytes,
+                    ts,
+                    &[("ProcessName", usage.name.as_str())],
+                ));
+            }
+        }
+        for disk in &measurement.disks {
+            let dimensions = [("MountPath", disk.mount_path.as_str())];
+            datums.push(self.datum_with(
+                "DiskUtilization",
+                disk.utilization(),
+                StandardUnit::Percent,
+                ts,
+                &dimensions,
+            ));
+            datums.push(self.datum_with(
+                "DiskUsed",
+                disk.used as f64,
+                StandardUnit::Bytes,
+                ts,
+                &dimensions,
+            ));
+            datums.push(self.datum_with(
+                "DiskAvailable",
+                disk.available as f64,
+                StandardUnit::Bytes,
+                ts,
+                &dimensions,
+            ));
+            if let Some(inode_utilization) = disk.inode_utilization() {
+                datums.push(self.datum_with(
+                    "InodeUtilization",
+                    inode_utilization,
+                    StandardUnit::Percent,
+                    ts,
+                    &dimensions,
+                ));
+            }
+        }
+        for network in &measurement.network {
+            let dimensions: Vec<(&str, &str)> = match &network.interface {
+                Some(interface) => vec![("Interface", interface.as_str())],
+                None => vec![],
+            };
+            let counters = &network.counters;
+            for (name, value, unit) in [
+                (
+                    "NetworkRxBytes",
+                    counters.rx_bytes,
+                    StandardUnit::BytesSecond,
+                ),
+                (
+                    "NetworkTxBytes",
+                    counters.tx_bytes,
+                    StandardUnit::BytesSecond,
+                ),
+                (
+                    "NetworkRxPackets",
+                    counters.rx_packets,
+                    StandardUnit::CountSecond,
+                ),
+                (
+                    "NetworkTxPackets",
+                    counters.tx_packets,
+                    StandardUnit::CountSecond,
+                ),
+                (
+                    "NetworkRxErrors",
+                    counters.rx_errors,
+                    StandardUnit::CountSecond,
+                ),
+                (
+                    "NetworkTxErrors",
+                    counters.tx_errors,
+                    StandardUnit::CountSecond,
+                ),
+                (
+                    "NetworkRxDropped",
+                    counters.rx_dropped,
+                    StandardUnit::CountSecond,
+                ),
+                (
+                    "NetworkTxDropped",
+                    counters.tx_dropped,
+                    StandardUnit::CountSecond,
+                ),
+            ] {
+                datums.push(self.datum_with(name, network.rate(value), unit, ts, &dimensions));
+            }
+        }
+        if let Some(sockets) = &measurement.sockets {
+            for (name, value) in [
+                ("TCPEstablished", sockets.established),
+                ("TCPSynSent", sockets.syn_sent),
+                ("TCPTimeWait", sockets.time_wait),
+                ("TCPCloseWait", sockets.close_wait),
+            ] {
+                datums.push(self.datum(name, value as f64, StandardUnit::Count, ts));
+            }
+            if let Some(used) = sockets.used {
+                datums.push(self.datum("SocketsUsed", used as f64, StandardUnit::Count, ts));
+            }
+            if let Some(orphan) = sockets.orphan {
+                datums.push(self.datum("TCPOrphan", orphan as f64, StandardUnit::Count, ts));
+            }
+            if let Some(tcp_memory) = sockets.tcp_memory {
+                datums.push(self.datum("TCPMemory", tcp_memory as f64, StandardUnit::Bytes, ts));
+            }
+        }
+        datums
+    }
+
+    /// Convert custom metrics of the agent namespace to datums
+    fn custom_datums(&self, measurement: &Measurement) -> Vec<MetricDatum> {
+        measurement
+            .custom
+            .iter()
+            .filter(|m| m.key.namespace.is_none())
+            .map(|m| self.custom_datum(m, measurement.timestamp))
+            .collect()
+    }
+
+    /// Convert custom metrics with their own namespaces to datums by namespace
+    fn namespaced_datums<'a>(
+        &self,
+        measurement: &'a Measurement,
+    ) -> BTreeMap<&'a str, Vec<MetricDatum>> {
+        let mut namespaces: BTreeMap<&str, Vec<MetricDatum>> = BTreeMap::new();
+        for metric in &measurement.custom {
+            if let Some(namespace) = &metric.key.namespace {
+                namespaces
+                    .entry(namespace)
+                    .or_default()
+                    .push(self.custom_datum(metric, measurement.timestamp));
+            }
+        }
+        namespaces
+    }
+}
+
 #[async_trait]
 impl MetricPublisher for CloudwatchPublisher {
     async fn send(&mut self, measurement: Measurement) -> Result<(), Box<dyn std::error::Error>> {
         info!("Sending measurement to CloudWatch {:?}", measurement);
 
-        let mut request_builder = self
-            .client
-            .put_metric_data()
-            .namespace(&self.config.namespace);
-
-        request_builder = request_builder.metric_data(
-            MetricDatum::builder()
-                .dimensions(
-                    Dimension::builder()
-                        .name("ServiceName")
-                        .value(&self.config.service_name)
-                        .build(),
-                )
-                .metric_name("CPUUtilization")
-                .value(measurement.cpu_utilization)
-                .timestamp(measurement.timestamp.into())
-                .unit(StandardUnit::Percent)
-                .build(),
-        );
-        request_builder = request_builder.metric_data(
-            MetricDatum::builder()
-                .dimensions(
-                    Dimension::builder()
-                        .name("ServiceName")
-                        .value(&self.config.service_name)
-                        .build(),
-                )
-                .metric_name("MemoryUtilization")
-                .value(measurement.mem_utilization)
-                .timestamp(measurement.timestamp.into())
-                .unit(StandardUnit::Percent)
-                .build(),
-        );
-        request_builder = request_builder.metric_data(
-            MetricDatum::builder()
-                .dimensions(
-                    Dimension::builder()
-                        .name("ServiceName")
-                        .value(&self.config.service_name)
-                        .build(),
-                )
-                .metric_name("MaxMemoryUtilization")
-                .value(measurement.max_mem_utilization)
-                .timestamp(measurement.timestamp.into())
-                .unit(StandardUnit::Percent)
-                .build(),
-        );
-        if let Err(err) = request_builder.send().await {
-            Err(err.into())
-        } else {
-            Ok(())
+        let result = self
+            .put_datums(
+                &self.config.namespace,
+                self.measurement_datums(&measurement),
+            )
+            .await;
+
+        // custom metrics are sent separately so a rejected one does not drop system metrics,
+        // their failures are only logged
+        let _ = self
+            .put_datums(&self.config.namespace, self.custom_datums(&measurement))
+            .await;
+
+        // application namespaces go after the agent one and cannot fail it
+        for (namespace, datums) in self.namespaced_datums(&measurement) {
+            let _ = self.put_datums(namespace, datums).await;
         }
+        result.map_err(|err| err as Box<dyn std::error::Error>)
     }
 }
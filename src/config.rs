@@ -1,5 +1,171 @@
+use clap::ValueEnum;
+use hyper::Uri;
+use regex::Regex;
+use std::fmt;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::str::FromStr;
+
 #[derive(Debug)]
 pub struct CloudwatchConfig {
     pub namespace: String,
     pub service_name: String,
 }
+
+/// Definition of used memory for memory utilization
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum MemoryMode {
+    /// Cgroup usage if memory limit is set, otherwise total memory without available memory
+    #[default]
+    Auto,
+    /// Memory usage including page cache
+    Usage,
+    /// Memory usage without inactive page cache
+    WorkingSet,
+    /// Anonymous memory and swap
+    RssSwap,
+    /// Total memory without available (free and reclaimable) memory
+    Available,
+}
+
+impl fmt::Display for MemoryMode {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // reuse names of command line values
+        match self.to_possible_value() {
+            Some(value) => write!(fmt, "{}", value.get_name()),
+            None => write!(fmt, "{:?}", self),
+        }
+    }
+}
+
+/// Rule to find processes of a monitored group
+#[derive(Debug, Clone)]
+pub enum ProcessMatcher {
+    /// Executable name
+    Name(String),
+    /// Regular expression matching the command line
+    Cmdline(Regex),
+    /// File with a process identifier
+    PidFile(PathBuf),
+}
+
+/// Monitored process group, labeled by the selector value
+#[derive(Debug, Clone)]
+pub struct ProcessSelector {
+    pub label: String,
+    pub matcher: ProcessMatcher,
+}
+
+impl FromStr for ProcessSelector {
+    type Err = String;
+
+    /// Parse `name:VALUE`, `cmdline:REGEX` or `pidfile:PATH`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (kind, value) = s
+            .split_once(':')
+            .filter(|(_, value)| !value.is_empty())
+            .ok_or_else(|| {
+                format!(
+                    "expected name:VALUE, cmdline:REGEX or pidfile:PATH, got {}",
+                    s
+                )
+            })?;
+        let matcher = match kind {
+            "name" => ProcessMatcher::Name(value.to_string()),
+            "cmdline" => ProcessMatcher::Cmdline(Regex::new(value).map_err(|e| e.to_string())?),
+            "pidfile" => ProcessMatcher::PidFile(PathBuf::from(value)),
+            _ => return Err(format!("unknown process selector {}", kind)),
+        };
+        Ok(ProcessSelector {
+            label: value.to_string(),
+            matcher,
+        })
+    }
+}
+
+/// Default share of stolen CPU time considered as contention
+pub const DEFAULT_STEAL_THRESHOLD: f64 = 0.1;
+
+/// Default length of the CPU steal window in seconds
+pub const DEFAULT_STEAL_WINDOW: u32 = 300;
+
+/// Settings of metrics collection
+#[derive(Debug, Clone)]
+pub struct CollectorConfig {
+    pub memory_mode: MemoryMode,
+    /// Publish utilization of each CPU core
+    pub per_core: bool,
+    /// Mount points for disk space metrics, detected automatically if empty
+    pub mount_points: Vec<String>,
+    /// Publish network traffic summed over interfaces instead of per interface
+    pub sum_interfaces: bool,
+    /// Local ports to count TCP connections for, all ports if empty
+    pub tcp_ports: Vec<u16>,
+    /// Process groups to monitor
+    pub processes: Vec<ProcessSelector>,
+    /// Number of heaviest processes to publish, disabled if None
+    pub top_processes: Option<usize>,
+    /// Share of stolen CPU time considered as contention
+    pub steal_threshold: f64,
+    /// Length of the CPU steal window in seconds
+    pub steal_window: u32,
+    /// UDP address to receive StatsD metrics on, disabled if None
+    pub statsd_address: Option<SocketAddr>,
+    /// Unix datagram socket to receive StatsD metrics on, disabled if None
+    pub statsd_socket: Option<PathBuf>,
+    /// TCP and UDP address to receive Embedded Metric Format documents on, disabled if None
+    pub emf_address: Option<SocketAddr>,
+    /// Prometheus endpoints to scrape
+    pub prometheus_targets: Vec<Uri>,
+    /// Scraped metric names to publish, all if empty
+    pub prometheus_metrics: Vec<Regex>,
+    /// Scraped labels to publish as dimensions
+    pub prometheus_labels: Vec<String>,
+}
+
+impl Default for CollectorConfig {
+    fn default() -> Self {
+        CollectorConfig {
+            memory_mode: MemoryMode::default(),
+            per_core: false,
+            mount_points: vec![],
+            sum_interfaces: false,
+            tcp_ports: vec![],
+            processes: vec![],
+            top_processes: None,
+            steal_threshold: DEFAULT_STEAL_THRESHOLD,
+            steal_window: DEFAULT_STEAL_WINDOW,
+            statsd_address: None,
+            statsd_socket: None,
+            emf_address: None,
+            prometheus_targets: vec![],
+            prometheus_metrics: vec![],
+            prometheus_labels: vec![],
+        }
+    }
+}
+
+/// Tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_process_selector() {
+        let selector: ProcessSelector = "name:nginx".parse().unwrap();
+        assert_eq!(selector.label, "nginx");
+        assert!(matches!(selector.matcher, ProcessMatcher::Name(name) if name == "nginx"));
+
+        let selector: ProcessSelector = "cmdline:python .*worker\\.py".parse().unwrap();
+        assert_eq!(selector.label, "python .*worker\\.py");
+        assert!(matches!(selector.matcher, ProcessMatcher::Cmdline(_)));
+
+        let selector: ProcessSelector = "pidfile:/run/app.pid".parse().unwrap();
+        assert!(matches!(selector.matcher, ProcessMatcher::PidFile(_)));
+
+        assert!("nginx".parse::<ProcessSelector>().is_err());
+        assert!("name:".parse::<ProcessSelector>().is_err());
+        assert!("cmdline:(".parse::<ProcessSelector>().is_err());
+        assert!("exe:nginx".parse::<ProcessSelector>().is_err());
+    }
+}
@@ -0,0 +1,525 @@
+use crate::cgroups::*;
+use crate::metrics::median;
+
+use log::debug;
+use std::time::Instant;
+use sysinfo::{CpuExt, System, SystemExt};
+
+/// Kernel and system statistics of the host
+pub(crate) const PROC_STAT: &str = "/proc/stat";
+
+/// CPU counters observed at the previous measurement
+#[derive(Default)]
+pub struct CpuState {
+    last_usage: Option<(u64, Instant)>,
+    last_throttling: Option<ThrottlingMeasurement>,
+    last_times: Option<CpuTimes>,
+}
+
+/// Time spent by host CPUs in each mode, in clock ticks
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct CpuTimes {
+    pub user: u64,
+    pub nice: u64,
+    pub system: u64,
+    pub idle: u64,
+    pub iowait: u64,
+    pub irq: u64,
+    pub softirq: u64,
+    pub steal: u64,
+}
+
+impl CpuTimes {
+    /// Total time, guest time is already accounted in user time
+    pub(crate) fn total(&self) -> u64 {
+        self.user
+            + self.nice
+            + self.system
+            + self.idle
+            + self.iowait
+            + self.irq
+            + self.softirq
+            + self.steal
+    }
+
+    /// Share of total time spent in a mode
+    pub fn ratio(&self, value: u64) -> f64 {
+        let total = self.total();
+        if total > 0 {
+            value as f64 / total as f64
+        } else {
+            0.0
+        }
+    }
+
+    /// Counters increment since the previous cumulative value
+    fn delta(&self, prev: &CpuTimes) -> CpuTimes {
+        CpuTimes {
+            user: self.user.saturating_sub(prev.user),
+            nice: self.nice.saturating_sub(prev.nice),
+            system: self.system.saturating_sub(prev.system),
+            idle: self.idle.saturating_sub(prev.idle),
+            iowait: self.iowait.saturating_sub(prev.iowait),
+            irq: self.irq.saturating_sub(prev.irq),
+            softirq: self.softirq.saturating_sub(prev.softirq),
+            steal: self.steal.saturating_sub(prev.steal),
+        }
+    }
+}
+
+/// Utilization of a single host CPU core
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct CoreMeasurement {
+    pub core: usize,
+    pub utilization: f64,
+    pub max_utilization: f64,
+}
+
+/// CPU throttling by CFS quota
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ThrottlingMeasurement {
+    /// Number of elapsed enforcement periods
+    pub periods: u64,
+    /// Number of periods when the cgroup was throttled
+    pub throttled_periods: u64,
+    /// Total time the cgroup was throttled, in microseconds
+    pub throttled_usec: u64,
+}
+
+impl ThrottlingMeasurement {
+    /// Share of enforcement periods with throttling
+    pub fn throttled_ratio(&self) -> f64 {
+        if self.periods > 0 {
+            self.throttled_periods as f64 / self.periods as f64
+        } else {
+            0.0
+        }
+    }
+
+    /// Counters increment since the previous cumulative value
+    fn delta(&self, prev: &ThrottlingMeasurement) -> ThrottlingMeasurement {
+        ThrottlingMeasurement {
+            periods: self.periods.saturating_sub(prev.periods),
+            throttled_periods: self
+                .throttled_periods
+                .saturating_sub(prev.throttled_periods),
+            throttled_usec: self.throttled_usec.saturating_sub(prev.throttled_usec),
+        }
+    }
+}
+
+/// Read cumulative CPU time consumed by the cgroup, in nanoseconds
+fn read_cgroups_usage(cgroup: &Cgroup) -> Result<u64, Box<dyn std::error::Error>> {
+    let err = std::io::Error::from(std::io::ErrorKind::NotFound);
+    match cgroup.version {
+        CgroupVersion::V1 => read_value(&cgroup.file("cpuacct", "cpuacct.usage")),
+        CgroupVersion::V2 => {
+            let stat = read_keyed(&cgroup.file("cpu", "cpu.stat"))?;
+            let usage_usec = stat.get("usage_usec").ok_or_else(|| Box::new(err))?;
+            Ok(usage_usec * 1000)
+        }
+    }
+}
+
+/// Parse cgroups v2 cpu.max content into a quota in CPUs
+///
+/// Content format is `$MAX $PERIOD` where `$MAX` could be `max` for no quota
+fn parse_cpu_max(content: &str) -> Option<f64> {
+    let mut parts = content.split_whitespace();
+    let quota = parts.next()?.parse::<u64>().ok()?;
+    let period = parts.next()?.parse::<u64>().ok()?;
+    if period == 0 {
+        return None;
+    }
+    Some(quota as f64 / period as f64)
+}
+
+/// Read CPU quota of the cgroup in CPUs, returns None if no quota is imposed
+pub fn read_cgroups_quota(cgroup: &Cgroup) -> Result<Option<f64>, Box<dyn std::error::Error>> {
+    match cgroup.version {
+        CgroupVersion::V1 => {
+            // quota is -1 if not set
+            let quota = std::fs::read_to_string(cgroup.file("cpu", "cpu.cfs_quota_us"))?
+                .trim()
+                .parse::<i64>()?;
+            let period = read_value(&cgroup.file("cpu", "cpu.cfs_period_us"))?;
+            if quota <= 0 || period == 0 {
+                return Ok(None);
+            }
+            Ok(Some(quota as f64 / period as f64))
+        }
+        CgroupVersion::V2 => Ok(parse_cpu_max(&std::fs::read_to_string(
+            cgroup.file("cpu", "cpu.max"),
+        )?)),
+    }
+}
+
+/// Parse a CPU list like `0-3,8,10-11` into a number of CPUs
+fn parse_cpu_list(content: &str) -> Option<usize> {
+    let mut count = 0;
+    for range in content.trim().split(',').filter(|range| !range.is_empty()) {
+        count += match range.split_once('-') {
+            Some((first, last)) => {
+                let first = first.parse::<usize>().ok()?;
+                let last = last.parse::<usize>().ok()?;
+                last.checked_sub(first)? + 1
+            }
+            None => {
+                range.parse::<usize>().ok()?;
+                1
+            }
+        };
+    }
+    (count > 0).then_some(count)
+}
+
+/// Read number of CPUs the cgroup is allowed to run on
+fn read_cgroups_cpuset(cgroup: &Cgroup) -> Option<usize> {
+    let names = match cgroup.version {
+        CgroupVersion::V1 => ["cpuset.effective_cpus", "cpuset.cpus"],
+        CgroupVersion::V2 => ["cpuset.cpus.effective", "cpuset.cpus"],
+    };
+    names.iter().find_map(|name| {
+        let content = std::fs::read_to_string(cgroup.file("cpuset", name)).ok()?;
+        parse_cpu_list(&content)
+    })
+}
+
+/// Number of CPUs available to the agent considering cgroup cpuset and quota
+pub fn effective_cpu_count(sys: &System, cgroup: Option<&Cgroup>) -> f64 {
+    let mut count = sys.cpus().len() as f64;
+    if let Some(cgroup) = cgroup {
+        if let Some(cpuset) = read_cgroups_cpuset(cgroup) {
+            count = count.min(cpuset as f64);
+        }
+        if let Ok(Some(quota)) = read_cgroups_quota(cgroup) {
+            count = count.min(quota);
+        }
+    }
+    count
+}
+
+/// Parse aggregated CPU times from /proc/stat content
+///
+/// Line format is:
+/// cpu user nice system idle iowait irq softirq steal guest guest_nice
+fn parse_cpu_times(content: &str) -> Option<CpuTimes> {
+    let line = content.lines().find(|line| line.starts_with("cpu "))?;
+    let values: Vec<u64> = line
+        .split_whitespace()
+        .skip(1)
+        .map(|value| value.parse::<u64>().ok())
+        .collect::<Option<_>>()?;
+    if values.len() < 8 {
+        return None;
+    }
+    Some(CpuTimes {
+        user: values[0],
+        nice: values[1],
+        system: values[2],
+        idle: values[3],
+        iowait: values[4],
+        irq: values[5],
+        softirq: values[6],
+        steal: values[7],
+    })
+}
+
+/// Sum CPU times over measurements
+pub fn aggregate_cpu_times(series: &[&CpuTimes]) -> Option<CpuTimes> {
+    if series.is_empty() {
+        return None;
+    }
+    Some(CpuTimes {
+        user: series.iter().map(|t| t.user).sum(),
+        nice: series.iter().map(|t| t.nice).sum(),
+        system: series.iter().map(|t| t.system).sum(),
+        idle: series.iter().map(|t| t.idle).sum(),
+        iowait: series.iter().map(|t| t.iowait).sum(),
+        irq: series.iter().map(|t| t.irq).sum(),
+        softirq: series.iter().map(|t| t.softirq).sum(),
+        steal: series.iter().map(|t| t.steal).sum(),
+    })
+}
+
+/// Take median and max utilization of each core over measurements
+pub fn aggregate_cores(series: &[&[CoreMeasurement]]) -> Vec<CoreMeasurement> {
+    let core_count = series.iter().map(|cores| cores.len()).max().unwrap_or(0);
+    (0..core_count)
+        .map(|core| {
+            let samples: Vec<&CoreMeasurement> = series
+                .iter()
+                .filter_map(|cores| cores.iter().find(|c| c.core == core))
+                .collect();
+            CoreMeasurement {
+                core,
+                utilization: median(samples.iter().map(|c| c.utilization).collect()),
+                max_utilization: samples
+                    .iter()
+                    .map(|c| c.max_utilization)
+                    .fold(0.0, f64::max),
+            }
+        })
+        .collect()
+}
+
+/// Sum throttling counters over measurements, None if no throttling is known
+pub fn aggregate_throttling(series: &[&ThrottlingMeasurement]) -> Option<ThrottlingMeasurement> {
+    if series.is_empty() {
+        return None;
+    }
+    Some(ThrottlingMeasurement {
+        periods: series.iter().map(|t| t.periods).sum(),
+        throttled_periods: series.iter().map(|t| t.throttled_periods).sum(),
+        throttled_usec: series.iter().map(|t| t.throttled_usec).sum(),
+    })
+}
+
+/// Read cumulative throttling counters from cpu.stat
+///
+/// Cgroups v1 reports throttled time in nanoseconds while v2 uses microseconds
+fn read_cgroups_throttling(
+    cgroup: &Cgroup,
+) -> Result<ThrottlingMeasurement, Box<dyn std::error::Error>> {
+    let err = std::io::Error::from(std::io::ErrorKind::NotFound);
+    let stat = read_keyed(&cgroup.file("cpu", "cpu.stat"))?;
+    let throttled_usec = match cgroup.version {
+        CgroupVersion::V1 => stat.get("throttled_time").map(|value| value / 1000),
+        CgroupVersion::V2 => stat.get("throttled_usec").copied(),
+    };
+    if let (Some(&periods), Some(&throttled_periods), Some(throttled_usec)) = (
+        stat.get("nr_periods"),
+        stat.get("nr_throttled"),
+        throttled_usec,
+    ) {
+        return Ok(ThrottlingMeasurement {
+            periods,
+            throttled_periods,
+            throttled_usec,
+        });
+    }
+    Err(Box::new(err))
+}
+
+/// Detect CPU throttling since the previous measurement
+fn collect_throttling_cgroups(
+    cgroup: &Cgroup,
+    state: &mut CpuState,
+) -> Option<ThrottlingMeasurement> {
+    let throttling = read_cgroups_throttling(cgroup).ok()?;
+    let last_throttling = state.last_throttling.replace(throttling.clone());
+    Some(throttling.delta(&last_throttling?))
+}
+
+/// Detect CPU utilization relative to cgroup CPU quota
+/// Works only if CPU quota is set (it is a case for ECS tasks with CPU limits)
+fn collect_cpu_cgroups(cgroup: &Cgroup, state: &mut CpuState) -> Option<f64> {
+    let quota = read_cgroups_quota(cgroup).ok()??;
+    let usage = read_cgroups_usage(cgroup).ok()?;
+    let now = Instant::now();
+    let last_usage = state.last_usage.replace((usage, now));
+    let (prev_usage, prev_time) = last_usage?;
+    let elapsed = now.duration_since(prev_time).as_nanos() as f64;
+    if elapsed <= 0.0 {
+        return None;
+    }
+    let used = usage.saturating_sub(prev_usage) as f64;
+    debug!(
+        "Got cgroups {} cpu usage {} ns in {} ns with quota {}",
+        cgroup.version, used, elapsed, quota
+    );
+    // usage can slightly exceed quota due to accounting granularity
+    Some((used / (elapsed * quota)).min(1.0))
+}
+
+/// Detect CPU utilization averaged across all host CPUs
+fn collect_cpu_sysinfo(sys: &mut System) -> f64 {
+    let cpu_count = sys.cpus().len();
+    let cpu_sum: f64 = sys.cpus().iter().map(|p| p.cpu_usage() as f64).sum();
+    if cpu_count > 0 && !cpu_sum.is_nan() {
+        cpu_sum / (cpu_count as f64) / 100.0
+    } else {
+        0.0
+    }
+}
+
+/// Detect utilization of each host CPU core
+pub fn collect_cores(sys: &mut System) -> Vec<CoreMeasurement> {
+    sys.cpus()
+        .iter()
+        .enumerate()
+        .map(|(core, cpu)| {
+            let usage = cpu.cpu_usage() as f64;
+            let utilization = if usage.is_nan() { 0.0 } else { usage / 100.0 };
+            CoreMeasurement {
+                core,
+                utilization,
+                max_utilization: utilization,
+            }
+        })
+        .collect()
+}
+
+/// Write CPU info to writer
+pub fn collect_cpu_info<W: std::fmt::Write>(f: &mut W, sys: &mut System, cgroup: Option<&Cgroup>) {
+    writeln!(f, "Sysinfo: cpu count: {}", sys.cpus().len()).unwrap();
+    if let Some(cgroup) = cgroup {
+        match read_cgroups_quota(cgroup) {
+            Ok(Some(quota)) => {
+                writeln!(f, "cgroups {}: cpu quota {:.3}", cgroup.version, quota).unwrap()
+            }
+            _ => writeln!(f, "cgroups {}: no cpu quota", cgroup.version).unwrap(),
+        }
+    }
+}
+
+/// Read cumulative time spent by host CPUs in each mode since boot
+pub(crate) fn read_cpu_times() -> Option<CpuTimes> {
+    let content = std::fs::read_to_string(PROC_STAT).ok()?;
+    parse_cpu_times(&content)
+}
+
+/// Detect time spent by host CPUs in each mode since the previous measurement
+pub fn collect_cpu_times(state: &mut CpuState) -> Option<CpuTimes> {
+    let times = read_cpu_times()?;
+    let last_times = state.last_times.replace(times.clone());
+    Some(times.delta(&last_times?))
+}
+
+/// Remember initial CPU time so the first measurement has a baseline
+pub fn init_cpu(state: &mut CpuState, cgroup: Option<&Cgroup>) {
+    let _ = collect_cpu_times(state);
+    if let Some(cgroup) = cgroup {
+        let _ = collect_cpu_cgroups(cgroup, state);
+        let _ = collect_throttling_cgroups(cgroup, state);
+    }
+}
+
+/// Detect CPU throttling, available only for cgroups
+pub fn collect_throttling(
+    cgroup: Option<&Cgroup>,
+    state: &mut CpuState,
+) -> Option<ThrottlingMeasurement> {
+    cgroup.and_then(|cgroup| collect_throttling_cgroups(cgroup, state))
+}
+
+/// Detect CPU utilization
+pub fn collect_cpu(sys: &mut System, cgroup: Option<&Cgroup>, state: &mut CpuState) -> f64 {
+    if let Some(utilization) = cgroup.and_then(|cgroup| collect_cpu_cgroups(cgroup, state)) {
+        return utilization;
+    }
+    collect_cpu_sysinfo(sys)
+}
+
+/// Tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn test_parse_cpu_max() {
+        assert_eq!(parse_cpu_max("50000 100000\n"), Some(0.5));
+        assert_eq!(parse_cpu_max("max 100000\n"), None);
+    }
+
+    #[test]
+    fn test_parse_cpu_times() {
+        let content = "cpu  100 10 50 800 20 5 5 10 0 0\n\
+                       cpu0 50 5 25 400 10 2 3 5 0 0\n\
+                       intr 12345\n";
+        let times = parse_cpu_times(content).unwrap();
+        assert_eq!(times.total(), 1000);
+        assert!((times.ratio(times.steal) - 0.01).abs() < 0.0001);
+        assert!(parse_cpu_times("intr 12345\n").is_none());
+    }
+
+    #[test]
+    fn test_parse_cpu_list() {
+        assert_eq!(parse_cpu_list("0-3,8,10-11\n"), Some(7));
+        assert_eq!(parse_cpu_list("0\n"), Some(1));
+        assert_eq!(parse_cpu_list("\n"), None);
+    }
+
+    #[test]
+    fn test_aggregate_cores() {
+        let sample = |values: &[f64]| -> Vec<CoreMeasurement> {
+            values
+                .iter()
+                .enumerate()
+                .map(|(core, &value)| CoreMeasurement {
+                    core,
+                    utilization: value,
+                    max_utilization: value,
+                })
+                .collect()
+        };
+        let series = [
+            sample(&[0.1, 1.0]),
+            sample(&[0.3, 0.2]),
+            sample(&[0.2, 0.4]),
+        ];
+        let cores = aggregate_cores(&series.iter().map(|s| s.as_slice()).collect::<Vec<_>>());
+        assert_eq!(cores.len(), 2);
+        assert!((cores[0].utilization - 0.2).abs() < 0.0001);
+        assert!((cores[1].utilization - 0.4).abs() < 0.0001);
+        assert!((cores[1].max_utilization - 1.0).abs() < 0.0001);
+    }
+
+    #[test]
+    fn test_collect_cpu_cgroups_v1() {
+        let root = create_fixture(
+            "cpu-v1",
+            &[
+                ("proc/cgroup", "1:cpu:/\n"),
+                ("mnt/cpu/cpu.cfs_quota_us", "-1\n"),
+                ("mnt/cpu/cpu.cfs_period_us", "100000\n"),
+                ("mnt/cpuacct/cpuacct.usage", "1000000000\n"),
+            ],
+        );
+        let cgroup = Cgroup::detect_at(&root.join("mnt"), &root.join("proc/cgroup")).unwrap();
+        let mut state = CpuState::default();
+        assert!(read_cgroups_quota(&cgroup).unwrap().is_none());
+        assert!(collect_cpu_cgroups(&cgroup, &mut state).is_none());
+
+        // a quarter of CPU second used in a second with a half CPU quota
+        std::fs::write(root.join("mnt/cpu/cpu.cfs_quota_us"), "50000\n").unwrap();
+        state.last_usage = Some((750_000_000, Instant::now() - Duration::from_secs(1)));
+        let utilization = collect_cpu_cgroups(&cgroup, &mut state).unwrap();
+        assert!((utilization - 0.5).abs() < 0.01);
+    }
+
+    #[test]
+    fn test_collect_throttling_cgroups_v2() {
+        let root = create_fixture(
+            "throttling-v2",
+            &[
+                ("proc/cgroup", "0::/\n"),
+                ("mnt/cgroup.controllers", "cpu memory\n"),
+                (
+                    "mnt/cpu.stat",
+                    "usage_usec 100\nnr_periods 10\nnr_throttled 2\nthrottled_usec 5000\n",
+                ),
+            ],
+        );
+        let cgroup = Cgroup::detect_at(&root.join("mnt"), &root.join("proc/cgroup")).unwrap();
+        let mut state = CpuState::default();
+        assert!(collect_throttling_cgroups(&cgroup, &mut state).is_none());
+
+        std::fs::write(
+            root.join("mnt/cpu.stat"),
+            "usage_usec 200\nnr_periods 20\nnr_throttled 7\nthrottled_usec 9000\n",
+        )
+        .unwrap();
+        let throttling = collect_throttling_cgroups(&cgroup, &mut state).unwrap();
+        assert_eq!(
+            throttling,
+            ThrottlingMeasurement {
+                periods: 10,
+                throttled_periods: 5,
+                throttled_usec: 4000,
+            }
+        );
+        assert!((throttling.throttled_ratio() - 0.5).abs() < 0.001);
+    }
+}
@@ -0,0 +1,348 @@
+use log::{debug, warn};
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+
+/// Maximum number of distinct custom metrics kept during a period
+const MAX_CUSTOM_METRICS: usize = 1000;
+
+/// Maximum number of dimensions of a CloudWatch metric
+const MAX_DIMENSIONS: usize = 30;
+
+/// Maximum length of metric and dimension names
+const MAX_NAME_LENGTH: usize = 255;
+
+/// Maximum length of a dimension value
+const MAX_DIMENSION_VALUE_LENGTH: usize = 1024;
+
+/// Identity of a custom metric
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct MetricKey {
+    /// Namespace of a metric published without agent dimensions,
+    /// None for the agent namespace
+    pub namespace: Option<String>,
+    pub name: String,
+    /// Dimensions sorted by name
+    pub dimensions: Vec<(String, String)>,
+    /// CloudWatch unit name like Count or Milliseconds
+    pub unit: String,
+}
+
+impl MetricKey {
+    pub fn new(name: &str, mut dimensions: Vec<(String, String)>, unit: &str) -> MetricKey {
+        dimensions.sort();
+        MetricKey {
+            namespace: None,
+            name: name.to_string(),
+            dimensions,
+            unit: unit.to_string(),
+        }
+    }
+
+    /// Publish the metric to its own namespace with only its dimensions
+    pub fn with_namespace(mut self, namespace: &str) -> MetricKey {
+        self.namespace = Some(namespace.to_string());
+        self
+    }
+
+    /// Check the key against CloudWatch limits, metrics of the agent namespace
+    /// also get the ServiceName dimension
+    pub fn validate(&self) -> Result<(), String> {
+        let valid_name = |name: &str| {
+            !name.trim().is_empty() && name.len() <= MAX_NAME_LENGTH && name.is_ascii()
+        };
+        if !valid_name(&self.name) {
+            return Err(format!("invalid metric name {}", self.name));
+        }
+        if let Some(namespace) = &self.namespace {
+            let allowed = |c: char| c.is_ascii_alphanumeric() || ".-_/#: ".contains(c);
+            // AWS/ namespaces are reserved for AWS services
+            if !valid_name(namespace)
+                || !namespace.chars().all(allowed)
+                || namespace.starts_with("AWS/")
+            {
+                return Err(format!("invalid namespace {}", namespace));
+            }
+        }
+        let max_dimensions = match self.namespace {
+            Some(_) => MAX_DIMENSIONS,
+            None => MAX_DIMENSIONS - 1,
+        };
+        if self.dimensions.len() > max_dimensions {
+            return Err(format!(
+                "metric {} has {} dimensions, at most {} allowed",
+                self.name,
+                self.dimensions.len(),
+                max_dimensions
+            ));
+        }
+        for (name, value) in &self.dimensions {
+            if !valid_name(name) || name.starts_with(':') {
+                return Err(format!("invalid dimension name {}", name));
+            }
+            if value.trim().is_empty() || value.chars().count() > MAX_DIMENSION_VALUE_LENGTH {
+                return Err(format!("invalid value of dimension {}", name));
+            }
+            if self.namespace.is_none() && name == "ServiceName" {
+                return Err("dimension ServiceName is reserved".to_string());
+            }
+        }
+        // dimensions are sorted so duplicates are adjacent
+        if let Some(pair) = self.dimensions.windows(2).find(|p| p[0].0 == p[1].0) {
+            return Err(format!("duplicate dimension {}", pair[0].0));
+        }
+        Ok(())
+    }
+}
+
+/// Value submitted by an application
+#[derive(Debug, Clone, PartialEq)]
+pub enum Sample {
+    /// Increment of a counter
+    Counter(f64),
+    /// Current value of a gauge
+    Gauge(f64),
+    /// Change of a gauge value
+    GaugeDelta(f64),
+    /// Observed value of a distribution with a weight from the sample rate
+    Distribution { value: f64, weight: f64 },
+    /// Statistics of values observed by an application
+    Statistics {
+        min: f64,
+        max: f64,
+        sum: f64,
+        count: f64,
+    },
+    /// Member of a set of unique values
+    Set(String),
+}
+
+/// Value of a custom metric over a period
+#[derive(Debug, Clone, PartialEq)]
+pub enum CustomValue {
+    Value(f64),
+    Statistics {
+        min: f64,
+        max: f64,
+        sum: f64,
+        count: f64,
+    },
+}
+
+/// Custom metric aggregated over a period
+#[derive(Debug, Clone, PartialEq)]
+pub struct CustomMetric {
+    pub key: MetricKey,
+    pub value: CustomValue,
+}
+
+/// Samples of a custom metric received during a period
+enum Accumulator {
+    Counter(f64),
+    Gauge(f64),
+    Distribution {
+        min: f64,
+        max: f64,
+        sum: f64,
+        count: f64,
+    },
+    Set(HashSet<String>),
+}
+
+impl Accumulator {
+    fn new(sample: &Sample) -> Accumulator {
+        match sample {
+            Sample::Counter(_) => Accumulator::Counter(0.0),
+            Sample::Gauge(_) | Sample::GaugeDelta(_) => Accumulator::Gauge(0.0),
+            Sample::Distribution { value, .. } => Accumulator::Distribution {
+                min: *value,
+                max: *value,
+                sum: 0.0,
+                count: 0.0,
+            },
+            Sample::Statistics { min, max, .. } => Accumulator::Distribution {
+                min: *min,
+                max: *max,
+                sum: 0.0,
+                count: 0.0,
+            },
+            Sample::Set(_) => Accumulator::Set(HashSet::new()),
+        }
+    }
+
+    /// Add a sample of the same kind, false if kinds differ
+    fn add(&mut self, sample: Sample) -> bool {
+        match (self, sample) {
+            (Accumulator::Counter(total), Sample::Counter(value)) => *total += value,
+            (Accumulator::Gauge(current), Sample::Gauge(value)) => *current = value,
+            (Accumulator::Gauge(current), Sample::GaugeDelta(delta)) => *current += delta,
+            (
+                Accumulator::Distribution {
+                    min,
+                    max,
+                    sum,
+                    count,
+                },
+                Sample::Distribution { value, weight },
+            ) => {
+                *min = min.min(value);
+                *max = max.max(value);
+                *sum += value * weight;
+                *count += weight;
+            }
+            (
+                Accumulator::Distribution {
+                    min,
+                    max,
+                    sum,
+                    count,
+                },
+                Sample::Statistics {
+                    min: other_min,
+                    max: other_max,
+                    sum: other_sum,
+                    count: other_count,
+                },
+            ) => {
+                *min = min.min(other_min);
+                *max = max.max(other_max);
+                *sum += other_sum;
+                *count += other_count;
+            }
+            (Accumulator::Set(values), Sample::Set(value)) => {
+                values.insert(value);
+            }
+            _ => return false,
+        }
+        true
+    }
+
+    fn value(&self) -> CustomValue {
+        match self {
+            Accumulator::Counter(value) | Accumulator::Gauge(value) => CustomValue::Value(*value),
+            Accumulator::Distribution {
+                min,
+                max,
+                sum,
+                count,
+            } => CustomValue::Statistics {
+                min: *min,
+                max: *max,
+                sum: *sum,
+                count: *count,
+            },
+            Accumulator::Set(values) => CustomValue::Value(values.len() as f64),
+        }
+    }
+}
+
+/// Custom metrics of the current period
+#[derive(Default)]
+struct Period {
+    metrics: HashMap<MetricKey, Accumulator>,
+    /// Samples dropped because of too many distinct metrics
+    dropped: u64,
+}
+
+/// Custom metrics received from applications, shared between listeners and the collector
+#[derive(Clone, Default)]
+pub struct CustomMetrics {
+    period: Arc<Mutex<Period>>,
+}
+
+impl CustomMetrics {
+    /// Add a sample to the metric of the current period
+    pub fn record(&self, key: MetricKey, sample: Sample) {
+        let mut period = self.period.lock().unwrap();
+        if !period.metrics.contains_key(&key) && period.metrics.len() >= MAX_CUSTOM_METRICS {
+            // warn once per period, the total is reported on drain
+            if period.dropped == 0 {
+                warn!("Too many custom metrics, dropping new ones until the next period");
+            }
+            period.dropped += 1;
+            return;
+        }
+        let accumulator = period
+            .metrics
+            .entry(key)
+            .or_insert_with(|| Accumulator::new(&sample));
+        if !accumulator.add(sample.clone()) {
+            // the metric changed its type, start over
+            debug!("Custom metric type changed to {:?}", sample);
+            *accumulator = Accumulator::new(&sample);
+            accumulator.add(sample);
+        }
+    }
+
+    /// Take metrics of the current period and start a new one
+    pub fn drain(&self) -> Vec<CustomMetric> {
+        let period = std::mem::take(&mut *self.period.lock().unwrap());
+        if period.dropped > 0 {
+            warn!("Dropped {} custom metric samples", period.dropped);
+        }
+        let mut metrics: Vec<CustomMetric> = period
+            .metrics
+            .into_iter()
+            .map(|(key, accumulator)| CustomMetric {
+                value: accumulator.value(),
+                key,
+            })
+            .collect();
+        metrics.sort_by(|a, b| a.key.cmp(&b.key));
+        metrics
+    }
+}
+
+/// Tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_custom_metrics() {
+        let custom = CustomMetrics::default();
+        let key = |name: &str| MetricKey::new(name, vec![], "None");
+        custom.record(key("requests"), Sample::Counter(1.0));
+        custom.record(key("requests"), Sample::Counter(2.0));
+        custom.record(key("queue"), Sample::Gauge(5.0));
+        custom.record(key("queue"), Sample::GaugeDelta(-2.0));
+        for value in [30.0, 10.0, 20.0] {
+            let sample = Sample::Distribution { value, weight: 1.0 };
+            custom.record(key("latency"), sample);
+        }
+        custom.record(key("users"), Sample::Set("alice".to_string()));
+        custom.record(key("users"), Sample::Set("bob".to_string()));
+        custom.record(key("users"), Sample::Set("alice".to_string()));
+
+        let metrics = custom.drain();
+        let value = |name: &str| {
+            metrics
+                .iter()
+                .find(|m| m.key.name == name)
+                .map(|m| m.value.clone())
+        };
+        assert_eq!(value("requests"), Some(CustomValue::Value(3.0)));
+        assert_eq!(value("queue"), Some(CustomValue::Value(3.0)));
+        assert_eq!(
+            value("latency"),
+            Some(CustomValue::Statistics {
+                min: 10.0,
+                max: 30.0,
+                sum: 60.0,
+                count: 3.0
+            })
+        );
+        assert_eq!(value("users"), Some(CustomValue::Value(2.0)));
+        // the next period starts empty
+        assert!(custom.drain().is_empty());
+
+        // new metrics over the limit are dropped until the next period
+        for i in 0..MAX_CUSTOM_METRICS + 2 {
+            custom.record(key(&format!("metric{}", i)), Sample::Counter(1.0));
+        }
+        custom.record(key("metric0"), Sample::Counter(1.0));
+        assert_eq!(custom.period.lock().unwrap().dropped, 2);
+        let metrics = custom.drain();
+        assert_eq!(metrics.len(), MAX_CUSTOM_METRICS);
+        assert_eq!(custom.period.lock().unwrap().dropped, 0);
+    }
+}
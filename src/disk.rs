@@ -0,0 +1,217 @@
+use log::debug;
+use std::ffi::CString;
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+
+/// Mount table of the current mount namespace
+const PROC_SELF_MOUNTINFO: &str = "/proc/self/mountinfo";
+
+/// Filesystems that never hold user data
+const PSEUDO_FILESYSTEMS: [&str; 22] = [
+    "autofs",
+    "binfmt_misc",
+    "bpf",
+    "cgroup",
+    "cgroup2",
+    "configfs",
+    "debugfs",
+    "devpts",
+    "devtmpfs",
+    "efivarfs",
+    "fusectl",
+    "hugetlbfs",
+    "mqueue",
+    "nsfs",
+    "overlay",
+    "proc",
+    "pstore",
+    "rpc_pipefs",
+    "securityfs",
+    "sysfs",
+    "tmpfs",
+    "tracefs",
+];
+
+/// Disk space of a mounted filesystem
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct DiskMeasurement {
+    pub mount_path: String,
+    /// Used space in bytes
+    pub used: u64,
+    /// Space available to unprivileged users in bytes
+    pub available: u64,
+    /// Used inodes
+    pub inodes_used: u64,
+    /// Total inodes, zero if filesystem has no fixed inode count
+    pub inodes_total: u64,
+}
+
+impl DiskMeasurement {
+    /// Share of used space as reported by df
+    pub fn utilization(&self) -> f64 {
+        let total = self.used + self.available;
+        if total > 0 {
+            self.used as f64 / total as f64
+        } else {
+            0.0
+        }
+    }
+
+    pub fn inode_utilization(&self) -> Option<f64> {
+        if self.inodes_total > 0 {
+            Some(self.inodes_used as f64 / self.inodes_total as f64)
+        } else {
+            None
+        }
+    }
+}
+
+/// Decode octal escapes like `\040` used for spaces in mountinfo
+fn unescape_mount_path(path: &str) -> String {
+    let mut result = String::with_capacity(path.len());
+    let mut rest = path;
+    while let Some(pos) = rest.find('\\') {
+        result.push_str(&rest[..pos]);
+        let code = rest.get(pos + 1..pos + 4);
+        match code.and_then(|code| u8::from_str_radix(code, 8).ok()) {
+            Some(byte) => {
+                result.push(byte as char);
+                rest = &rest[pos + 4..];
+            }
+            None => {
+                result.push('\\');
+                rest = &rest[pos + 1..];
+            }
+        }
+    }
+    result.push_str(rest);
+    result
+}
+
+/// Find writable mount points with real filesystems in mountinfo content
+///
+/// Line format is:
+/// 36 35 98:0 /mnt1 /mnt/parent rw,noatime master:1 - ext3 /dev/root rw,errors=continue
+fn parse_writable_mounts(content: &str) -> Vec<String> {
+    content
+        .lines()
+        .filter_map(|line| {
+            let (mount, filesystem) = line.split_once(" - ")?;
+            let mut mount_parts = mount.split_whitespace().skip(4);
+            let mount_path = unescape_mount_path(mount_parts.next()?);
+            let options = mount_parts.next()?;
+            let fstype = filesystem.split_whitespace().next()?;
+            let writable = options.split(',').any(|option| option == "rw");
+            let system_path = ["/proc", "/sys", "/dev"]
+                .iter()
+                .any(|prefix| Path::new(&mount_path).starts_with(prefix));
+            if !writable || system_path || PSEUDO_FILESYSTEMS.contains(&fstype) {
+                return None;
+            }
+            Some(mount_path)
+        })
+        .collect()
+}
+
+/// Mount points to monitor when none are configured: root and writable volumes
+pub fn default_mount_points() -> Vec<String> {
+    let mut mount_points = vec!["/".to_string()];
+    if let Ok(content) = std::fs::read_to_string(PROC_SELF_MOUNTINFO) {
+        for mount_path in parse_writable_mounts(&content) {
+            // bind-mounted files like /etc/hosts are not volumes
+            if !mount_points.contains(&mount_path) && Path::new(&mount_path).is_dir() {
+                mount_points.push(mount_path);
+            }
+        }
+    }
+    mount_points
+}
+
+/// Query filesystem statistics of a mount point
+// field types of statvfs differ between platforms
+#[allow(clippy::unnecessary_cast)]
+fn statvfs(mount_path: &str) -> Result<DiskMeasurement, Box<dyn std::error::Error>> {
+    let c_path = CString::new(Path::new(mount_path).as_os_str().as_bytes())?;
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+    // SAFETY: path is a valid C string and stat is a properly sized output buffer
+    if unsafe { libc::statvfs(c_path.as_ptr(), &mut stat) } != 0 {
+        return Err(Box::new(std::io::Error::last_os_error()));
+    }
+    let fragment_size = stat.f_frsize as u64;
+    Ok(DiskMeasurement {
+        mount_path: mount_path.to_string(),
+        used: (stat.f_blocks as u64).saturating_sub(stat.f_bfree as u64) * fragment_size,
+        available: stat.f_bavail as u64 * fragment_size,
+        inodes_used: (stat.f_files as u64).saturating_sub(stat.f_ffree as u64),
+        inodes_total: stat.f_files as u64,
+    })
+}
+
+/// Write disk info to writer
+pub fn collect_disk_info<W: std::fmt::Write>(f: &mut W, mount_points: &[String]) {
+    for mount_path in mount_points {
+        match statvfs(mount_path) {
+            Ok(disk) => writeln!(
+                f,
+                "Disk: {} used {}, available {}",
+                mount_path, disk.used, disk.available
+            )
+            .unwrap(),
+            Err(err) => writeln!(f, "Disk: {} is not available: {}", mount_path, err).unwrap(),
+        }
+    }
+}
+
+/// Detect disk space of mount points
+pub fn collect_disks(mount_points: &[String]) -> Vec<DiskMeasurement> {
+    mount_points
+        .iter()
+        .filter_map(|mount_path| match statvfs(mount_path) {
+            Ok(disk) => Some(disk),
+            Err(err) => {
+                debug!("Cannot query disk {}: {}", mount_path, err);
+                None
+            }
+        })
+        .collect()
+}
+
+/// Take the latest disk space of each mount point over measurements
+pub fn aggregate_disks(series: &[&[DiskMeasurement]]) -> Vec<DiskMeasurement> {
+    let mut disks: Vec<DiskMeasurement> = vec![];
+    for disk in series.iter().flat_map(|disks| disks.iter()) {
+        match disks.iter_mut().find(|d| d.mount_path == disk.mount_path) {
+            Some(existing) => *existing = disk.clone(),
+            None => disks.push(disk.clone()),
+        }
+    }
+    disks
+}
+
+/// Tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_writable_mounts() {
+        let content = "\
+            22 1 0:21 / / rw,relatime - overlay overlay rw,lowerdir=/l\n\
+            23 22 0:22 / /proc rw,nosuid - proc proc rw\n\
+            24 22 259:1 /vol /data rw,relatime - ext4 /dev/nvme1n1 rw\n\
+            25 22 259:1 /cfg /config ro,relatime - ext4 /dev/nvme1n1 ro\n\
+            26 22 259:1 /x /my\\040volume rw,relatime shared:1 - xfs /dev/nvme1n1 rw\n";
+        assert_eq!(
+            parse_writable_mounts(content),
+            vec!["/data".to_string(), "/my volume".to_string()]
+        );
+    }
+
+    #[test]
+    fn test_collect_root_disk() {
+        let disks = collect_disks(&["/".to_string(), "/nonexistent".to_string()]);
+        assert_eq!(disks.len(), 1);
+        assert_eq!(disks[0].mount_path, "/");
+        assert!(disks[0].utilization() <= 1.0);
+    }
+}
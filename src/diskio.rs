@@ -0,0 +1,310 @@
+use crate::cgroups::*;
+use crate::cpu::read_cgroups_quota;
+
+use log::debug;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+/// Block device statistics of the host
+const PROC_DISKSTATS: &str = "/proc/diskstats";
+
+/// Size of a sector in /proc/diskstats
+const SECTOR_SIZE: u64 = 512;
+
+/// Block devices that duplicate or do not represent physical IO
+const VIRTUAL_DEVICE_PREFIXES: [&str; 4] = ["loop", "ram", "dm-", "md"];
+
+/// Block IO counters
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct IoCounters {
+    pub read_bytes: u64,
+    pub write_bytes: u64,
+    pub read_ops: u64,
+    pub write_ops: u64,
+}
+
+impl IoCounters {
+    /// Counters increment since the previous cumulative value
+    fn delta(&self, prev: &IoCounters) -> IoCounters {
+        IoCounters {
+            read_bytes: self.read_bytes.saturating_sub(prev.read_bytes),
+            write_bytes: self.write_bytes.saturating_sub(prev.write_bytes),
+            read_ops: self.read_ops.saturating_sub(prev.read_ops),
+            write_ops: self.write_ops.saturating_sub(prev.write_ops),
+        }
+    }
+}
+
+/// Block IO during a time interval
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct IoMeasurement {
+    pub counters: IoCounters,
+    pub elapsed: Duration,
+}
+
+impl IoMeasurement {
+    /// Convert a counter to a per-second rate
+    pub fn rate(&self, value: u64) -> f64 {
+        let seconds = self.elapsed.as_secs_f64();
+        if seconds > 0.0 {
+            value as f64 / seconds
+        } else {
+            0.0
+        }
+    }
+}
+
+/// IO counters observed at the previous measurement
+#[derive(Default)]
+pub struct IoState {
+    last: Option<(IoCounters, Instant)>,
+}
+
+/// Parse cgroups v2 io.stat content
+///
+/// Line format is:
+/// 259:0 rbytes=1024 wbytes=2048 rios=1 wios=2 dbytes=0 dios=0
+fn parse_io_stat(content: &str) -> IoCounters {
+    let mut counters = IoCounters::default();
+    for part in content
+        .lines()
+        .flat_map(|line| line.split_whitespace().skip(1))
+    {
+        let Some((key, value)) = part.split_once('=') else {
+            continue;
+        };
+        let value = value.parse::<u64>().unwrap_or(0);
+        match key {
+            "rbytes" => counters.read_bytes += value,
+            "wbytes" => counters.write_bytes += value,
+            "rios" => counters.read_ops += value,
+            "wios" => counters.write_ops += value,
+            _ => (),
+        }
+    }
+    counters
+}
+
+/// Parse cgroups v1 blkio.throttle files into read and write values
+///
+/// Line format is `8:0 Read 1024`, with a final `Total 4096` line
+fn parse_blkio_throttle(content: &str) -> (u64, u64) {
+    let mut read = 0;
+    let mut write = 0;
+    for line in content.lines() {
+        let parts: Vec<&str> = line.split_whitespace().collect();
+        if let [_, op, value] = parts[..] {
+            let value = value.parse::<u64>().unwrap_or(0);
+            match op {
+                "Read" => read += value,
+                "Write" => write += value,
+                _ => (),
+            }
+        }
+    }
+    (read, write)
+}
+
+/// Parse /proc/diskstats content for whole physical devices
+///
+/// Line format is:
+/// 259 0 nvme0n1 reads merged sectors_read ms writes merged sectors_written ...
+fn parse_diskstats(content: &str, is_whole_device: impl Fn(&str) -> bool) -> IoCounters {
+    let mut counters = IoCounters::default();
+    for line in content.lines() {
+        let parts: Vec<&str> = line.split_whitespace().collect();
+        if parts.len() < 10 {
+            continue;
+        }
+        let name = parts[2];
+        if VIRTUAL_DEVICE_PREFIXES
+            .iter()
+            .any(|prefix| name.starts_with(prefix))
+            || !is_whole_device(name)
+        {
+            continue;
+        }
+        let field = |index: usize| parts[index].parse::<u64>().unwrap_or(0);
+        counters.read_ops += field(3);
+        counters.read_bytes += field(5) * SECTOR_SIZE;
+        counters.write_ops += field(7);
+        counters.write_bytes += field(9) * SECTOR_SIZE;
+    }
+    counters
+}
+
+/// Whether the cgroup is a container with a memory limit or CPU quota,
+/// like memory and CPU utilization are measured against
+///
+/// A cgroup of a host service or session has IO counters of the agent only
+fn is_limited(cgroup: &Cgroup) -> bool {
+    let memory_limit = match cgroup.version {
+        CgroupVersion::V1 => cgroup.file("memory", "memory.limit_in_bytes"),
+        CgroupVersion::V2 => cgroup.file("memory", "memory.max"),
+    };
+    matches!(read_limit(&memory_limit), Ok(Some(_)))
+        || matches!(read_cgroups_quota(cgroup), Ok(Some(_)))
+}
+
+/// Source of IO counters, cgroup-local one is used for limited containers
+fn io_source(cgroup: Option<&Cgroup>) -> Option<PathBuf> {
+    if let Some(cgroup) = cgroup.filter(|cgroup| is_limited(cgroup)) {
+        let path = match cgroup.version {
+            CgroupVersion::V1 => cgroup.file("blkio", "blkio.throttle.io_service_bytes"),
+            CgroupVersion::V2 => cgroup.file("io", "io.stat"),
+        };
+        if path.exists() {
+            return Some(path);
+        }
+    }
+    let path = PathBuf::from(PROC_DISKSTATS);
+    if path.exists() {
+        return Some(path);
+    }
+    None
+}
+
+/// Read cumulative IO counters
+fn read_io(cgroup: Option<&Cgroup>) -> Result<IoCounters, Box<dyn std::error::Error>> {
+    let source = io_source(cgroup).ok_or("no IO statistics")?;
+    let content = std::fs::read_to_string(&source)?;
+    if source == Path::new(PROC_DISKSTATS) {
+        // partitions have no entry in /sys/block
+        return Ok(parse_diskstats(&content, |name| {
+            Path::new("/sys/block").join(name).exists()
+        }));
+    }
+    match cgroup {
+        Some(cgroup) if cgroup.version == CgroupVersion::V1 => {
+            let (read_bytes, write_bytes) = parse_blkio_throttle(&content);
+            let serviced =
+                std::fs::read_to_string(cgroup.file("blkio", "blkio.throttle.io_serviced"))?;
+            let (read_ops, write_ops) = parse_blkio_throttle(&serviced);
+            Ok(IoCounters {
+                read_bytes,
+                write_bytes,
+                read_ops,
+                write_ops,
+            })
+        }
+        _ => Ok(parse_io_stat(&content)),
+    }
+}
+
+/// Sum IO over measurements
+pub fn aggregate_io(series: &[&IoMeasurement]) -> Option<IoMeasurement> {
+    if series.is_empty() {
+        return None;
+    }
+    Some(IoMeasurement {
+        counters: IoCounters {
+            read_bytes: series.iter().map(|io| io.counters.read_bytes).sum(),
+            write_bytes: series.iter().map(|io| io.counters.write_bytes).sum(),
+            read_ops: series.iter().map(|io| io.counters.read_ops).sum(),
+            write_ops: series.iter().map(|io| io.counters.write_ops).sum(),
+        },
+        elapsed: series.iter().map(|io| io.elapsed).sum(),
+    })
+}
+
+/// Write IO info to writer
+pub fn collect_io_info<W: std::fmt::Write>(f: &mut W, cgroup: Option<&Cgroup>) {
+    match io_source(cgroup) {
+        Some(path) => writeln!(f, "Disk IO: from {}", path.display()).unwrap(),
+        None => writeln!(f, "Disk IO: not available").unwrap(),
+    }
+}
+
+/// Detect IO since the previous measurement
+pub fn collect_io(cgroup: Option<&Cgroup>, state: &mut IoState) -> Option<IoMeasurement> {
+    let counters = match read_io(cgroup) {
+        Ok(counters) => counters,
+        Err(err) => {
+            debug!("Cannot read IO counters: {}", err);
+            return None;
+        }
+    };
+    let now = Instant::now();
+    let (prev_counters, prev_time) = state.last.replace((counters.clone(), now))?;
+    Some(IoMeasurement {
+        counters: counters.delta(&prev_counters),
+        elapsed: now.duration_since(prev_time),
+    })
+}
+
+/// Remember initial IO counters so the first measurement has a baseline
+pub fn init_io(state: &mut IoState, cgroup: Option<&Cgroup>) {
+    let _ = collect_io(cgroup, state);
+}
+
+/// Tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_io_stat() {
+        let counters = parse_io_stat(
+            "8:0 rbytes=100 wbytes=200 rios=1 wios=2 dbytes=0 dios=0\n\
+             8:16 rbytes=10 wbytes=20 rios=3 wios=4 dbytes=0 dios=0\n",
+        );
+        assert_eq!(
+            counters,
+            IoCounters {
+                read_bytes: 110,
+                write_bytes: 220,
+                read_ops: 4,
+                write_ops: 6,
+            }
+        );
+    }
+
+    #[test]
+    fn test_parse_blkio_throttle() {
+        let content = "8:0 Read 100\n8:0 Write 200\n8:0 Sync 300\n8:0 Total 300\nTotal 300\n";
+        assert_eq!(parse_blkio_throttle(content), (100, 200));
+    }
+
+    #[test]
+    fn test_io_source() {
+        let root = create_fixture(
+            "diskio-v2",
+            &[
+                ("proc/cgroup", "0::/\n"),
+                ("mnt/cgroup.controllers", "cpu io memory\n"),
+                ("mnt/io.stat", "8:0 rbytes=100 wbytes=200 rios=1 wios=2\n"),
+                ("mnt/memory.max", "max\n"),
+                ("mnt/cpu.max", "max 100000\n"),
+            ],
+        );
+        let cgroup = Cgroup::detect_at(&root.join("mnt"), &root.join("proc/cgroup")).unwrap();
+        let cgroup_stat = root.join("mnt/io.stat");
+        // a service cgroup without limits has IO of the agent only
+        assert_ne!(io_source(Some(&cgroup)), Some(cgroup_stat.clone()));
+
+        std::fs::write(root.join("mnt/memory.max"), "1024\n").unwrap();
+        assert_eq!(io_source(Some(&cgroup)), Some(cgroup_stat.clone()));
+
+        std::fs::write(root.join("mnt/memory.max"), "max\n").unwrap();
+        std::fs::write(root.join("mnt/cpu.max"), "50000 100000\n").unwrap();
+        assert_eq!(io_source(Some(&cgroup)), Some(cgroup_stat));
+    }
+
+    #[test]
+    fn test_parse_diskstats() {
+        let content = "\
+            259 0 nvme0n1 10 0 8 0 20 0 16 0 0 0 0\n\
+            259 1 nvme0n1p1 10 0 8 0 20 0 16 0 0 0 0\n\
+            7 0 loop0 5 0 4 0 0 0 0 0 0 0 0\n";
+        let counters = parse_diskstats(content, |name| name == "nvme0n1");
+        assert_eq!(
+            counters,
+            IoCounters {
+                read_bytes: 8 * 512,
+                write_bytes: 16 * 512,
+                read_ops: 10,
+                write_ops: 20,
+            }
+        );
+    }
+}
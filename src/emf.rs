@@ -0,0 +1,272 @@
+use crate::custom::{CustomMetrics, MetricKey, Sample};
+
+use log::{debug, error, info};
+use serde_json::{Map, Value};
+use std::net::SocketAddr;
+use tokio::io::{AsyncBufReadExt, BufReader};
+use tokio::net::{TcpListener, TcpStream, UdpSocket};
+
+/// Maximum size of a datagram
+const MAX_DATAGRAM_SIZE: usize = 65535;
+
+/// Maximum number of metrics in a directive and values of a metric
+const MAX_EMF_VALUES: usize = 100;
+
+/// Take a field of a JSON object as an array
+fn array<'a>(object: &'a Map<String, Value>, field: &str) -> Result<&'a Vec<Value>, String> {
+    object
+        .get(field)
+        .and_then(Value::as_array)
+        .ok_or_else(|| format!("expected {} array", field))
+}
+
+/// Parse an Embedded Metric Format document into metrics with their values
+///
+/// Document format is:
+/// {"_aws": {"CloudWatchMetrics": [{"Namespace": "App", "Dimensions": [["Route"]],
+///  "Metrics": [{"Name": "Latency", "Unit": "Milliseconds"}]}]},
+///  "Route": "api", "Latency": [12, 15]}
+fn parse_emf_document(content: &str) -> Result<Vec<(MetricKey, Vec<f64>)>, String> {
+    let document: Value = serde_json::from_str(content).map_err(|e| e.to_string())?;
+    let root = document.as_object().ok_or("expected an object")?;
+    let metadata = root
+        .get("_aws")
+        .and_then(Value::as_object)
+        .ok_or("no _aws metadata")?;
+    let mut metrics = vec![];
+    for directive in array(metadata, "CloudWatchMetrics")? {
+        let directive = directive.as_object().ok_or("expected a directive object")?;
+        let namespace = directive
+            .get("Namespace")
+            .and_then(Value::as_str)
+            .ok_or("no namespace")?;
+        // each dimension set references member values of the root object
+        let mut dimension_sets = vec![];
+        for dimension_set in array(directive, "Dimensions")? {
+            let dimensions: Vec<(String, String)> = dimension_set
+                .as_array()
+                .ok_or("expected a dimension set array")?
+                .iter()
+                .map(|name| {
+                    let name = name.as_str().ok_or("expected a dimension name")?;
+                    let value = root
+                        .get(name)
+                        .and_then(Value::as_str)
+                        .ok_or_else(|| format!("no value of dimension {}", name))?;
+                    Ok((name.to_string(), value.to_string()))
+                })
+                .collect::<Result<_, String>>()?;
+            dimension_sets.push(dimensions);
+        }
+        let definitions = array(directive, "Metrics")?;
+        if definitions.len() > MAX_EMF_VALUES {
+            return Err(format!("more than {} metrics", MAX_EMF_VALUES));
+        }
+        for definition in definitions {
+            let name = definition
+                .get("Name")
+                .and_then(Value::as_str)
+                .ok_or("no metric name")?;
+            let unit = definition
+                .get("Unit")
+                .and_then(Value::as_str)
+                .unwrap_or("None");
+            // a value is a number or an array of numbers
+            let values: Vec<f64> = match root.get(name) {
+                Some(Value::Array(values)) => values.iter().filter_map(Value::as_f64).collect(),
+                Some(value) => value.as_f64().into_iter().collect(),
+                None => return Err(format!("no value of metric {}", name)),
+            };
+            if values.len() > MAX_EMF_VALUES {
+                return Err(format!(
+                    "more than {} values of metric {}",
+                    MAX_EMF_VALUES, name
+                ));
+            }
+            for dimensions in &dimension_sets {
+                let key = MetricKey::new(name, dimensions.clone(), unit).with_namespace(namespace);
+                key.validate()?;
+                metrics.push((key, values.clone()));
+            }
+        }
+    }
+    Ok(metrics)
+}
+
+/// Record all metrics of a document
+fn handle_document(content: &str, custom: &CustomMetrics) {
+    match parse_emf_document(content) {
+        Ok(metrics) => {
+            for (key, values) in metrics {
+                for value in values {
+                    custom.record(key.clone(), Sample::Distribution { value, weight: 1.0 });
+                }
+            }
+        }
+        Err(err) => debug!("Cannot parse EMF document: {}", err),
+    }
+}
+
+/// Receive newline-delimited EMF documents from a TCP connection
+async fn receive_tcp(stream: TcpStream, custom: CustomMetrics) {
+    let mut lines = BufReader::new(stream).lines();
+    loop {
+        match lines.next_line().await {
+            Ok(Some(line)) if line.trim().is_empty() => (),
+            Ok(Some(line)) => handle_document(&line, &custom),
+            Ok(None) => break,
+            Err(err) => {
+                debug!("Cannot receive EMF documents: {}", err);
+                break;
+            }
+        }
+    }
+}
+
+/// Receive EMF documents from UDP datagrams
+async fn receive_udp(socket: UdpSocket, custom: CustomMetrics) {
+    let mut buf = vec![0; MAX_DATAGRAM_SIZE];
+    loop {
+        match socket.recv(&mut buf).await {
+            Ok(len) => {
+                let content = String::from_utf8_lossy(&buf[..len]);
+                for line in content.lines().filter(|line| !line.trim().is_empty()) {
+                    handle_document(line, &custom);
+                }
+            }
+            Err(err) => error!("Cannot receive EMF datagram: {}", err),
+        }
+    }
+}
+
+/// Accept TCP connections with EMF documents
+async fn accept_tcp(listener: TcpListener, custom: CustomMetrics) {
+    loop {
+        match listener.accept().await {
+            Ok((stream, peer)) => {
+                debug!("Accepted EMF connection from {}", peer);
+                tokio::spawn(receive_tcp(stream, custom.clone()));
+            }
+            Err(err) => error!("Cannot accept EMF connection: {}", err),
+        }
+    }
+}
+
+/// Task for receiving EMF documents on TCP and UDP ports of an address
+pub async fn emf_listener(address: SocketAddr, custom: CustomMetrics) {
+    let listener = match TcpListener::bind(address).await {
+        Ok(listener) => listener,
+        Err(err) => {
+            error!(
+                "Cannot listen for EMF documents on tcp {}: {}",
+                address, err
+            );
+            return;
+        }
+    };
+    let socket = match UdpSocket::bind(address).await {
+        Ok(socket) => socket,
+        Err(err) => {
+            error!(
+                "Cannot listen for EMF documents on udp {}: {}",
+                address, err
+            );
+            return;
+        }
+    };
+    info!("Listening for EMF documents on tcp and udp {}", address);
+    tokio::join!(
+        accept_tcp(listener, custom.clone()),
+        receive_udp(socket, custom)
+    );
+}
+
+/// Tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::custom::CustomValue;
+    use tokio::io::AsyncWriteExt;
+
+    #[test]
+    fn test_parse_emf_document() {
+        let content = r#"{
+            "_aws": {
+                "Timestamp": 1574109732004,
+                "CloudWatchMetrics": [{
+                    "Namespace": "App",
+                    "Dimensions": [["Route"], ["Route", "Method"], []],
+                    "Metrics": [{"Name": "Latency", "Unit": "Milliseconds"}, {"Name": "Hits"}]
+                }]
+            },
+            "Route": "api",
+            "Method": "GET",
+            "Latency": [12, 15],
+            "Hits": 1,
+            "RequestId": "abc"
+        }"#;
+        let metrics = parse_emf_document(content).unwrap();
+        assert_eq!(metrics.len(), 6);
+        let (key, values) = &metrics[1];
+        assert_eq!(key.namespace.as_deref(), Some("App"));
+        assert_eq!(key.name, "Latency");
+        assert_eq!(key.unit, "Milliseconds");
+        assert_eq!(key.dimensions.len(), 2);
+        assert_eq!(values, &vec![12.0, 15.0]);
+        let (key, values) = &metrics[5];
+        assert_eq!(key.unit, "None");
+        assert!(key.dimensions.is_empty());
+        assert_eq!(values, &vec![1.0]);
+
+        assert!(parse_emf_document(r#"{"Latency": 1}"#).is_err());
+        let missing_dimension = content.replace(r#""Method": "GET","#, "");
+        assert!(parse_emf_document(&missing_dimension).is_err());
+
+        // documents CloudWatch would reject
+        let reserved = content.replace(r#""App""#, r#""AWS/EC2""#);
+        assert!(parse_emf_document(&reserved).is_err());
+        let long_value = content.replace(r#""api""#, &format!(r#""{}""#, "a".repeat(1025)));
+        assert!(parse_emf_document(&long_value).is_err());
+        let names: Vec<String> = (0..31).map(|i| format!(r#""D{}""#, i)).collect();
+        let values: Vec<String> = (0..31).map(|i| format!(r#""D{}": "v""#, i)).collect();
+        let too_many = content
+            .replace(r#"["Route"], "#, &format!("[{}], ", names.join(",")))
+            .replace(r#""RequestId": "abc""#, &values.join(","));
+        assert!(parse_emf_document(&too_many).is_err());
+        let many_values: Vec<String> = (0..101).map(|i| i.to_string()).collect();
+        let many_values = content.replace("[12, 15]", &format!("[{}]", many_values.join(",")));
+        assert!(parse_emf_document(&many_values).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_receive_tcp() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let custom = CustomMetrics::default();
+        let task = tokio::spawn(accept_tcp(listener, custom.clone()));
+
+        let document = r#"{"_aws": {"CloudWatchMetrics": [{"Namespace": "App",
+            "Dimensions": [[]], "Metrics": [{"Name": "Latency"}]}]}, "Latency": 10}"#
+            .replace('\n', "");
+        let mut stream = TcpStream::connect(address).await.unwrap();
+        for _ in 0..2 {
+            stream.write_all(document.as_bytes()).await.unwrap();
+            stream.write_all(b"\nnot json\n").await.unwrap();
+        }
+        drop(stream);
+        tokio::time::sleep(std::time::Duration::from_millis(200)).await;
+        task.abort();
+
+        let metrics = custom.drain();
+        assert_eq!(metrics.len(), 1);
+        assert_eq!(
+            metrics[0].value,
+            CustomValue::Statistics {
+                min: 10.0,
+                max: 10.0,
+                sum: 20.0,
+                count: 2.0
+            }
+        );
+    }
+}
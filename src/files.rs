@@ -0,0 +1,120 @@
+use log::debug;
+use std::path::Path;
+
+/// Kernel file handle counters
+const PROC_FILE_NR: &str = "/proc/sys/fs/file-nr";
+
+/// System-wide file handle usage
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct FilesMeasurement {
+    /// Number of allocated file handles
+    pub allocated: u64,
+    /// Maximum number of file handles, file-max
+    pub max: u64,
+}
+
+impl FilesMeasurement {
+    pub fn utilization(&self) -> f64 {
+        if self.max > 0 {
+            self.allocated as f64 / self.max as f64
+        } else {
+            0.0
+        }
+    }
+}
+
+/// Parse file-nr content
+///
+/// Content format is `$ALLOCATED $UNUSED $MAX`, unused handles are
+/// always zero on modern kernels
+fn parse_file_nr(content: &str) -> Option<FilesMeasurement> {
+    let values: Vec<u64> = content
+        .split_whitespace()
+        .map(|value| value.parse::<u64>().ok())
+        .collect::<Option<_>>()?;
+    match values[..] {
+        [allocated, unused, max] => Some(FilesMeasurement {
+            allocated: allocated.saturating_sub(unused),
+            max,
+        }),
+        _ => None,
+    }
+}
+
+/// Parse soft limit of open files from /proc/<pid>/limits content
+///
+/// Line format is:
+/// Max open files            1024                 1048576              files
+pub(crate) fn parse_open_files_limit(content: &str) -> Option<u64> {
+    let line = content
+        .lines()
+        .find(|line| line.starts_with("Max open files"))?;
+    // soft limit could be `unlimited`
+    line["Max open files".len()..]
+        .split_whitespace()
+        .next()?
+        .parse::<u64>()
+        .ok()
+}
+
+/// Read soft limit of open files of a process
+pub(crate) fn read_open_files_limit(proc_dir: &Path) -> Option<u64> {
+    let content = std::fs::read_to_string(proc_dir.join("limits")).ok()?;
+    parse_open_files_limit(&content)
+}
+
+/// Take peak file handle usage over measurements
+pub fn aggregate_files(series: &[&FilesMeasurement]) -> Option<FilesMeasurement> {
+    if series.is_empty() {
+        return None;
+    }
+    Some(FilesMeasurement {
+        allocated: series.iter().map(|f| f.allocated).max().unwrap_or(0),
+        max: series.iter().map(|f| f.max).min().unwrap_or(0),
+    })
+}
+
+/// Write file handle info to writer
+pub fn collect_files_info<W: std::fmt::Write>(f: &mut W) {
+    match collect_files() {
+        Some(files) => writeln!(f, "Files: file-max {}", files.max).unwrap(),
+        None => writeln!(f, "Files: {} is not available", PROC_FILE_NR).unwrap(),
+    }
+}
+
+/// Detect system-wide file handle usage
+pub fn collect_files() -> Option<FilesMeasurement> {
+    match std::fs::read_to_string(PROC_FILE_NR) {
+        Ok(content) => parse_file_nr(&content),
+        Err(err) => {
+            debug!("Cannot read {}: {}", PROC_FILE_NR, err);
+            None
+        }
+    }
+}
+
+/// Tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_file_nr() {
+        let files = parse_file_nr("3000\t0\t10000\n").unwrap();
+        assert_eq!(files.allocated, 3000);
+        assert!((files.utilization() - 0.3).abs() < 0.0001);
+        assert!(parse_file_nr("3000\n").is_none());
+    }
+
+    #[test]
+    fn test_parse_open_files_limit() {
+        let content = "\
+Limit                     Soft Limit           Hard Limit           Units
+Max processes             63704                63704                processes
+Max open files            1024                 1048576              files
+";
+        assert_eq!(parse_open_files_limit(content), Some(1024));
+        let content = "Max open files            unlimited            unlimited            files\n";
+        assert_eq!(parse_open_files_limit(content), None);
+    }
+}
@@ -2,11 +2,28 @@
 //#![allow(unused_variables)]
 //#![allow(unused_imports)]
 
+mod cgroups;
 mod cloudwatch;
 pub mod config;
+mod cpu;
+mod custom;
+mod disk;
+mod diskio;
+mod emf;
+mod files;
+mod load;
 mod memory;
 mod metrics;
+mod network;
+mod pids;
+mod pressure;
+mod process;
+mod prometheus;
 mod publisher;
+mod sockets;
+mod statsd;
+mod steal;
+mod vmstat;
 
 use log::{debug, error, info, warn};
 use std::sync::Arc;
@@ -18,15 +35,20 @@ use tokio::sync::mpsc::error::TryRecvError;
 use tokio::sync::Mutex as TokioMutex;
 
 use crate::cloudwatch::create_cloudwatch_publisher;
-use crate::config::CloudwatchConfig;
+use crate::config::{CloudwatchConfig, CollectorConfig};
+use crate::custom::CustomMetrics;
+use crate::emf::emf_listener;
 use crate::metrics::*;
+use crate::prometheus::prometheus_scraper;
 use crate::publisher::{ConsolePublisher, MetricPublisher};
+use crate::statsd::{statsd_udp_listener, statsd_unix_listener};
 
 /// How often collect samples
 const MEASUREMENT_PERIOD: Duration = Duration::from_millis(900);
 
 /// Message between collector task and publisher task
 #[derive(Debug)]
+#[allow(clippy::large_enum_variant)]
 pub enum PublisherMessage {
     /// Metric aggregated measurements
     Metric(Measurement),
@@ -45,12 +67,14 @@ pub enum CollectorMessage {
 async fn metrics_collector(
     tx: mpsc::Sender<PublisherMessage>,
     rx_aggregation: &mut mpsc::Receiver<CollectorMessage>,
+    collector_config: CollectorConfig,
+    custom_metrics: CustomMetrics,
 ) {
-    let mut sys = create_measurement_engine();
+    let mut engine = create_measurement_engine(&collector_config);
 
     // Show metric information at first
     let mut buf = String::new();
-    collect_info(&mut buf, &mut sys);
+    collect_info(&mut buf, &mut engine);
     for line in buf.lines() {
         info!("Initial info: {}", line);
     }
@@ -60,15 +84,16 @@ async fn metrics_collector(
     loop {
         debug!("Metric tick");
 
-        let measurement = create_measurement(&mut sys);
+        let measurement = create_measurement(&mut engine);
         series.push(measurement);
 
         match rx_aggregation.try_recv() {
             Ok(message) => {
                 match message {
                     CollectorMessage::Aggregation => {
-                        if let Some(aggregated_measurement) = aggregate(&series) {
+                        if let Some(mut aggregated_measurement) = aggregate(&series) {
                             series.clear();
+                            aggregated_measurement.custom = custom_metrics.drain();
                             // now send
                             if let Err(err) = tx
                                 .send(PublisherMessage::Metric(aggregated_measurement))
@@ -165,6 +190,7 @@ pub async fn handle_shutdown(
 /// Entry point that orchestrate tasks and shutdown
 pub async fn main_runner(
     cloudwatch_config: CloudwatchConfig,
+    collector_config: CollectorConfig,
     dryrun: bool,
     period: u32,
 ) -> Result<(), aws_sdk_cloudwatch::Error> {
@@ -174,8 +200,34 @@ pub async fn main_runner(
     let (tx_aggregation, mut rx_aggregation) = mpsc::channel(4);
     let tx_collector_shutdown = tx_aggregation.clone();
 
+    // metrics received from applications are published with the system ones
+    let custom_metrics = CustomMetrics::default();
+    if let Some(address) = collector_config.statsd_address {
+        tokio::spawn(statsd_udp_listener(address, custom_metrics.clone()));
+    }
+    if let Some(path) = collector_config.statsd_socket.clone() {
+        tokio::spawn(statsd_unix_listener(path, custom_metrics.clone()));
+    }
+    if let Some(address) = collector_config.emf_address {
+        tokio::spawn(emf_listener(address, custom_metrics.clone()));
+    }
+    if !collector_config.prometheus_targets.is_empty() {
+        tokio::spawn(prometheus_scraper(
+            collector_config.prometheus_targets.clone(),
+            collector_config.prometheus_metrics.clone(),
+            collector_config.prometheus_labels.clone(),
+            custom_metrics.clone(),
+        ));
+    }
+
     let collector_task = tokio::spawn(async move {
-        metrics_collector(tx_metric, &mut rx_aggregation).await;
+        metrics_collector(
+            tx_metric,
+            &mut rx_aggregation,
+            collector_config,
+            custom_metrics,
+        )
+        .await;
     });
 
     let _aggregation_heartbeat_task = tokio::spawn(async move {
@@ -227,9 +279,20 @@ mod tests {
     async fn test_collector_multiple() {
         let (tx_metric, mut rx_metric) = mpsc::channel(4);
         let (tx_aggregation, mut rx_aggregation) = mpsc::channel(4);
+        let custom_metrics = CustomMetrics::default();
+        custom_metrics.record(
+            custom::MetricKey::new("requests", vec![], "Count"),
+            custom::Sample::Counter(1.0),
+        );
 
         let collect_task = tokio::spawn(async move {
-            metrics_collector(tx_metric, &mut rx_aggregation).await;
+            metrics_collector(
+                tx_metric,
+                &mut rx_aggregation,
+                CollectorConfig::default(),
+                custom_metrics,
+            )
+            .await;
         });
         // receive emitted measurements
         let received: Arc<TokioMutex<Vec<Measurement>>> = Arc::new(TokioMutex::new(vec![]));
@@ -251,6 +314,7 @@ mod tests {
         let messages = received.lock().await;
         assert_eq!(messages.len(), 1);
         assert_ge!(messages[0].sample_count, 3);
+        assert_eq!(messages[0].custom.len(), 1);
     }
 
     struct FakePublisher {
@@ -276,7 +340,13 @@ mod tests {
 
         let tx2 = tx_metric.clone();
         let collect_task = tokio::spawn(async move {
-            metrics_collector(tx_metric, &mut rx_aggregation).await;
+            metrics_collector(
+                tx_metric,
+                &mut rx_aggregation,
+                CollectorConfig::default(),
+                CustomMetrics::default(),
+            )
+            .await;
         });
         let fake_publisher = Arc::new(TokioMutex::new(FakePublisher {
             measurements: vec![],
@@ -330,7 +400,13 @@ mod tests {
         let tx2 = tx_metric.clone();
 
         let collect_task = tokio::spawn(async move {
-            metrics_collector(tx_metric, &mut rx_aggregation).await;
+            metrics_collector(
+                tx_metric,
+                &mut rx_aggregation,
+                CollectorConfig::default(),
+                CustomMetrics::default(),
+            )
+            .await;
         });
         let failure_publisher = Arc::new(TokioMutex::new(FailurePublisher {
             counter: 0,
@@ -367,7 +443,13 @@ mod tests {
         let tx_collector_shutdown = tx_aggregation.clone();
 
         let collect_task = tokio::spawn(async move {
-            metrics_collector(tx_metric, &mut rx_aggregation).await;
+            metrics_collector(
+                tx_metric,
+                &mut rx_aggregation,
+                CollectorConfig::default(),
+                CustomMetrics::default(),
+            )
+            .await;
         });
         let fake_publisher = Arc::new(TokioMutex::new(FakePublisher {
             measurements: vec![],
@@ -0,0 +1,67 @@
+use crate::cgroups::*;
+use crate::cpu::{effective_cpu_count, PROC_STAT};
+use crate::metrics::median;
+
+use log::debug;
+use std::path::Path;
+use sysinfo::{System, SystemExt};
+
+/// Load averages and run queue of the host
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct LoadMeasurement {
+    /// Load averages over 1, 5 and 15 minutes divided by effective CPU count
+    pub load1: f64,
+    pub load5: f64,
+    pub load15: f64,
+    /// Number of runnable tasks
+    pub procs_running: u64,
+    /// Number of tasks blocked on IO
+    pub procs_blocked: u64,
+}
+
+/// Take median of load values over measurements
+pub fn aggregate_load(series: &[&LoadMeasurement]) -> Option<LoadMeasurement> {
+    if series.is_empty() {
+        return None;
+    }
+    Some(LoadMeasurement {
+        load1: median(series.iter().map(|l| l.load1).collect()),
+        load5: median(series.iter().map(|l| l.load5).collect()),
+        load15: median(series.iter().map(|l| l.load15).collect()),
+        procs_running: median(series.iter().map(|l| l.procs_running as f64).collect()) as u64,
+        procs_blocked: median(series.iter().map(|l| l.procs_blocked as f64).collect()) as u64,
+    })
+}
+
+/// Write load info to writer
+pub fn collect_load_info<W: std::fmt::Write>(f: &mut W, sys: &System, cgroup: Option<&Cgroup>) {
+    writeln!(
+        f,
+        "Load: effective cpu count {:.3}",
+        effective_cpu_count(sys, cgroup)
+    )
+    .unwrap();
+}
+
+/// Detect load averages normalized by effective CPU count and run queue
+pub fn collect_load(sys: &System, cgroup: Option<&Cgroup>) -> Option<LoadMeasurement> {
+    let stat = match read_keyed(Path::new(PROC_STAT)) {
+        Ok(stat) => stat,
+        Err(err) => {
+            debug!("Cannot read {}: {}", PROC_STAT, err);
+            return None;
+        }
+    };
+    let cpu_count = effective_cpu_count(sys, cgroup);
+    if cpu_count <= 0.0 {
+        return None;
+    }
+    let load = sys.load_average();
+    Some(LoadMeasurement {
+        load1: load.one / cpu_count,
+        load5: load.five / cpu_count,
+        load15: load.fifteen / cpu_count,
+        procs_running: stat.get("procs_running").copied().unwrap_or(0),
+        procs_blocked: stat.get("procs_blocked").copied().unwrap_or(0),
+    })
+}
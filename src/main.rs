@@ -1,7 +1,14 @@
 use clap::Parser;
-use cloudwatch_metrics_agent::config::CloudwatchConfig;
+use cloudwatch_metrics_agent::config::{
+    CloudwatchConfig, CollectorConfig, MemoryMode, ProcessSelector, DEFAULT_STEAL_THRESHOLD,
+    DEFAULT_STEAL_WINDOW,
+};
 use cloudwatch_metrics_agent::main_runner;
+use hyper::Uri;
 use log::info;
+use regex::Regex;
+use std::net::SocketAddr;
+use std::path::PathBuf;
 
 #[derive(Debug, Parser)]
 struct Opt {
@@ -20,6 +27,85 @@ struct Opt {
     /// Whether to run without sending to CloudWatch
     #[arg(short, long)]
     dryrun: bool,
+
+    /// Whether to publish utilization of each CPU core
+    #[arg(long)]
+    per_core: bool,
+
+    /// Definition of used memory for memory utilization
+    #[arg(long, value_enum, default_value_t = MemoryMode::Auto)]
+    memory_mode: MemoryMode,
+
+    /// Mount point for disk space metrics, could be repeated.
+    /// Root and writable volumes are used by default
+    #[arg(long = "mount-point")]
+    mount_points: Vec<String>,
+
+    /// Whether to sum network traffic of all non-loopback interfaces
+    /// instead of publishing it per interface
+    #[arg(long)]
+    sum_interfaces: bool,
+
+    /// Local port to count TCP connections for, could be repeated.
+    /// All ports are used by default
+    #[arg(long = "tcp-port")]
+    tcp_ports: Vec<u16>,
+
+    /// Process group to monitor, could be repeated.
+    /// Format is name:EXECUTABLE, cmdline:REGEX or pidfile:PATH
+    #[arg(long = "process")]
+    processes: Vec<ProcessSelector>,
+
+    /// Publish this number of heaviest processes by CPU and by memory
+    #[arg(long)]
+    top_processes: Option<usize>,
+
+    /// Percent of stolen CPU time over the steal window considered as contention
+    #[arg(long, default_value_t = DEFAULT_STEAL_THRESHOLD * 100.0)]
+    steal_threshold: f64,
+
+    /// Length of the CPU steal window in seconds
+    #[arg(long, default_value_t = DEFAULT_STEAL_WINDOW)]
+    steal_window: u32,
+
+    /// UDP address to receive StatsD metrics on, e.g. 127.0.0.1:8125
+    #[arg(long)]
+    statsd_address: Option<SocketAddr>,
+
+    /// Unix datagram socket path to receive StatsD metrics on
+    #[arg(long)]
+    statsd_socket: Option<PathBuf>,
+
+    /// TCP and UDP address to receive CloudWatch Embedded Metric Format
+    /// documents on, e.g. 127.0.0.1:25888
+    #[arg(long)]
+    emf_address: Option<SocketAddr>,
+
+    /// Prometheus endpoint to scrape, e.g. http://127.0.0.1:9100/metrics,
+    /// could be repeated. Only http is supported
+    #[arg(long = "prometheus-target", value_parser = parse_http_uri, requires = "prometheus_metrics")]
+    prometheus_targets: Vec<Uri>,
+
+    /// Regular expression of scraped metric names to publish, could be repeated.
+    /// Required with Prometheus targets
+    #[arg(long = "prometheus-metric")]
+    prometheus_metrics: Vec<Regex>,
+
+    /// Scraped label to publish as a dimension, could be repeated.
+    /// Series differing in other labels are summed
+    #[arg(long = "prometheus-label")]
+    prometheus_labels: Vec<String>,
+}
+
+/// Parse a URI of a plain http endpoint
+fn parse_http_uri(value: &str) -> Result<Uri, String> {
+    let uri: Uri = value
+        .parse()
+        .map_err(|e: hyper::http::uri::InvalidUri| e.to_string())?;
+    match uri.scheme_str() {
+        Some("http") if uri.host().is_some() => Ok(uri),
+        _ => Err("expected an http URI like http://127.0.0.1:9100/metrics".to_string()),
+    }
 }
 
 #[tokio::main]
@@ -32,8 +118,25 @@ async fn main() -> Result<(), aws_sdk_cloudwatch::Error> {
         namespace: opt.namespace,
         service_name: opt.service_name,
     };
+    let collector_config = CollectorConfig {
+        memory_mode: opt.memory_mode,
+        per_core: opt.per_core,
+        mount_points: opt.mount_points,
+        sum_interfaces: opt.sum_interfaces,
+        tcp_ports: opt.tcp_ports,
+        processes: opt.processes,
+        top_processes: opt.top_processes,
+        steal_threshold: opt.steal_threshold / 100.0,
+        steal_window: opt.steal_window,
+        statsd_address: opt.statsd_address,
+        statsd_socket: opt.statsd_socket,
+        emf_address: opt.emf_address,
+        prometheus_targets: opt.prometheus_targets,
+        prometheus_metrics: opt.prometheus_metrics,
+        prometheus_labels: opt.prometheus_labels,
+    };
 
-    main_runner(cloudwatch_config, opt.dryrun, opt.period)
+    main_runner(cloudwatch_config, collector_config, opt.dryrun, opt.period)
         .await
         .unwrap();
 
@@ -1,102 +1,379 @@
+use crate::cgroups::*;
+use crate::config::MemoryMode;
+use crate::metrics::median;
+
 use log::debug;
-use std::fs::File;
-use std::io::BufRead;
+use std::collections::HashMap;
 use sysinfo::{System, SystemExt};
 
 pub struct MemoryMeasurement {
     pub utilization: f64,
     pub max_utilization: f64,
+    /// Used memory in bytes
+    pub used: u64,
+    /// Memory limit in bytes
+    pub limit: u64,
+    /// Peak used memory in bytes
+    pub max_used: u64,
 }
 
-fn read_cgroups_v1_usage() -> Result<u64, Box<dyn std::error::Error>> {
-    let err = std::io::Error::from(std::io::ErrorKind::NotFound);
-    if let Ok(file) = File::open("/sys/fs/cgroup/memory/memory.usage_in_bytes") {
-        // file content is a value in bytes
-        return Ok(std::io::BufReader::new(file)
-            .lines()
-            .next()
-            .ok_or_else(|| Box::new(err))??
-            .parse::<u64>()?);
+/// Split of used memory by kind
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct MemoryBreakdown {
+    /// Anonymous memory (RSS) in bytes
+    pub anon: u64,
+    /// Page cache including shared memory in bytes
+    pub file: u64,
+    /// Shared memory in bytes
+    pub shmem: u64,
+    /// Kernel slab memory in bytes, only for cgroups v2
+    pub slab: Option<u64>,
+    /// Used memory without inactive page cache in bytes
+    pub working_set: u64,
+}
+
+/// Swap usage
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SwapMeasurement {
+    /// Used swap in bytes
+    pub used: u64,
+    /// Peak used swap in bytes
+    pub max_used: u64,
+    /// Swap limit or total swap in bytes
+    pub total: u64,
+}
+
+impl SwapMeasurement {
+    pub fn utilization(&self) -> f64 {
+        self.used as f64 / self.total as f64
     }
-    Err(Box::new(err))
+
+    pub fn max_utilization(&self) -> f64 {
+        self.max_used as f64 / self.total as f64
+    }
+}
+
+/// Memory limit and OOM events
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct MemoryEventsMeasurement {
+    /// Times usage exceeded the high boundary, only for cgroups v2
+    pub high: Option<u64>,
+    /// Times usage hit the limit
+    pub max: Option<u64>,
+    /// Times OOM killer was invoked, only for cgroups v2
+    pub oom: Option<u64>,
+    /// Processes killed by OOM killer
+    pub oom_kill: Option<u64>,
 }
 
-fn read_cgroups_v1_max_usage() -> Result<u64, Box<dyn std::error::Error>> {
+impl MemoryEventsMeasurement {
+    /// Events since the previous cumulative value
+    fn delta(&self, prev: &MemoryEventsMeasurement) -> MemoryEventsMeasurement {
+        let delta = |current: Option<u64>, prev: Option<u64>| Some(current?.saturating_sub(prev?));
+        MemoryEventsMeasurement {
+            high: delta(self.high, prev.high),
+            max: delta(self.max, prev.max),
+            oom: delta(self.oom, prev.oom),
+            oom_kill: delta(self.oom_kill, prev.oom_kill),
+        }
+    }
+}
+
+/// Memory events observed at the previous measurement
+#[derive(Default)]
+pub struct MemoryState {
+    last_events: Option<MemoryEventsMeasurement>,
+}
+
+fn read_cgroups_v1_usage(cgroup: &Cgroup) -> Result<u64, Box<dyn std::error::Error>> {
+    // file content is a value in bytes
+    read_value(&cgroup.file("memory", "memory.usage_in_bytes"))
+}
+
+fn read_cgroups_v1_max_usage(cgroup: &Cgroup) -> Result<u64, Box<dyn std::error::Error>> {
+    // file content is a value in bytes
+    read_value(&cgroup.file("memory", "memory.max_usage_in_bytes"))
+}
+
+fn read_cgroups_stat(cgroup: &Cgroup) -> Result<HashMap<String, u64>, Box<dyn std::error::Error>> {
+    read_keyed(&cgroup.file("memory", "memory.stat"))
+}
+
+fn read_cgroups_v1_limit(cgroup: &Cgroup) -> Result<u64, Box<dyn std::error::Error>> {
     let err = std::io::Error::from(std::io::ErrorKind::NotFound);
-    if let Ok(file) = File::open("/sys/fs/cgroup/memory/memory.max_usage_in_bytes") {
-        // file content is a value in bytes
-        return Ok(std::io::BufReader::new(file)
-            .lines()
-            .next()
-            .ok_or_else(|| Box::new(err))??
-            .parse::<u64>()?);
+    let stat = read_cgroups_stat(cgroup)?;
+    // line format is:
+    // hierarchical_memory_limit 12345
+    // where the last value is a soft memory limit in bytes
+    if let Some(&value) = stat.get("hierarchical_memory_limit") {
+        if value < 0x7FFFFFFFFFFF0000 {
+            return Ok(value);
+        } else {
+            // If it contains a large value with zero bits in low 4 or 8 bits, no limit is imposed
+            debug!("cgroups v1 with no memory limit: {}", value);
+        }
     }
     Err(Box::new(err))
 }
 
-fn read_cgroups_v1_limit() -> Result<u64, Box<dyn std::error::Error>> {
+fn read_cgroups_v2_usage(cgroup: &Cgroup) -> Result<u64, Box<dyn std::error::Error>> {
+    read_value(&cgroup.file("memory", "memory.current"))
+}
+
+fn read_cgroups_v2_max_usage(cgroup: &Cgroup) -> Result<u64, Box<dyn std::error::Error>> {
+    // available since Linux 5.19
+    read_value(&cgroup.file("memory", "memory.peak"))
+}
+
+fn read_cgroups_v2_limit(cgroup: &Cgroup) -> Result<u64, Box<dyn std::error::Error>> {
     let err = std::io::Error::from(std::io::ErrorKind::NotFound);
-    if let Ok(file) = File::open("/sys/fs/cgroup/memory/memory.stat") {
-        if let Some(hier_line) = std::io::BufReader::new(file)
-            .lines()
-            .map_while(Result::ok)
-            .find(|s| s.starts_with("hierarchical_memory_limit "))
-        {
-            // line format is:
-            // hierarchical_memory_limit 12345
-            // where the last value is a soft memory limit in bytes
-            let value = hier_line
-                .split_whitespace()
-                .last()
-                .unwrap()
-                .parse::<u64>()?;
-            if value < 0x7FFFFFFFFFFF0000 {
-                return Ok(value);
-            } else {
-                // If it contains a large value with zero bits in low 4 or 8 bits, no limit is imposed
-                debug!("cgroups v1 with no memory limit: {}", value);
-                return Err(Box::new(err));
-            }
+    match read_limit(&cgroup.file("memory", "memory.max"))? {
+        Some(value) => Ok(value),
+        None => {
+            debug!("cgroups v2 with no memory limit");
+            Err(Box::new(err))
         }
     }
-    Err(Box::new(err))
+}
+
+fn read_cgroups_v1_events(
+    cgroup: &Cgroup,
+) -> Result<MemoryEventsMeasurement, Box<dyn std::error::Error>> {
+    // failcnt counts hits of the limit
+    let failcnt = read_value(&cgroup.file("memory", "memory.failcnt"))?;
+    // oom_kill is available since Linux 4.13
+    let oom_control = read_keyed(&cgroup.file("memory", "memory.oom_control"))?;
+    Ok(MemoryEventsMeasurement {
+        high: None,
+        max: Some(failcnt),
+        oom: None,
+        oom_kill: oom_control.get("oom_kill").copied(),
+    })
+}
+
+fn read_cgroups_v2_events(
+    cgroup: &Cgroup,
+) -> Result<MemoryEventsMeasurement, Box<dyn std::error::Error>> {
+    let events = read_keyed(&cgroup.file("memory", "memory.events"))?;
+    Ok(MemoryEventsMeasurement {
+        high: events.get("high").copied(),
+        max: events.get("max").copied(),
+        oom: events.get("oom").copied(),
+        oom_kill: events.get("oom_kill").copied(),
+    })
+}
+
+/// Split cgroups v1 memory usage using hierarchical counters of memory.stat
+fn parse_cgroups_v1_breakdown(stat: &HashMap<String, u64>, usage: u64) -> Option<MemoryBreakdown> {
+    Some(MemoryBreakdown {
+        anon: *stat.get("total_rss")?,
+        file: *stat.get("total_cache")?,
+        shmem: *stat.get("total_shmem")?,
+        slab: None,
+        working_set: usage.saturating_sub(*stat.get("total_inactive_file")?),
+    })
+}
+
+/// Split cgroups v2 memory usage using memory.stat
+fn parse_cgroups_v2_breakdown(stat: &HashMap<String, u64>, usage: u64) -> Option<MemoryBreakdown> {
+    Some(MemoryBreakdown {
+        anon: *stat.get("anon")?,
+        file: *stat.get("file")?,
+        shmem: *stat.get("shmem")?,
+        slab: stat.get("slab").copied(),
+        working_set: usage.saturating_sub(*stat.get("inactive_file")?),
+    })
+}
+
+/// Detect memory breakdown, available only for cgroups
+pub fn collect_memory_breakdown(cgroup: Option<&Cgroup>) -> Option<MemoryBreakdown> {
+    let cgroup = cgroup?;
+    let stat = read_cgroups_stat(cgroup).ok()?;
+    match cgroup.version {
+        CgroupVersion::V1 => parse_cgroups_v1_breakdown(&stat, read_cgroups_v1_usage(cgroup).ok()?),
+        CgroupVersion::V2 => parse_cgroups_v2_breakdown(&stat, read_cgroups_v2_usage(cgroup).ok()?),
+    }
+}
+
+/// Take median of memory breakdown over measurements
+pub fn aggregate_memory_breakdown(series: &[&MemoryBreakdown]) -> Option<MemoryBreakdown> {
+    if series.is_empty() {
+        return None;
+    }
+    let median_of = |field: fn(&MemoryBreakdown) -> u64| {
+        median(series.iter().map(|b| field(b) as f64).collect()) as u64
+    };
+    let slab: Vec<f64> = series
+        .iter()
+        .filter_map(|b| b.slab)
+        .map(|v| v as f64)
+        .collect();
+    Some(MemoryBreakdown {
+        anon: median_of(|b| b.anon),
+        file: median_of(|b| b.file),
+        shmem: median_of(|b| b.shmem),
+        slab: (!slab.is_empty()).then(|| median(slab) as u64),
+        working_set: median_of(|b| b.working_set),
+    })
+}
+
+/// Calculate used memory according to the mode from cgroup usage and memory.stat
+fn cgroups_used(cgroup: &Cgroup, mode: MemoryMode, usage: u64) -> Option<u64> {
+    let stat = read_cgroups_stat(cgroup).ok()?;
+    let (anon, file, shmem, inactive_file, swap) = match cgroup.version {
+        CgroupVersion::V1 => (
+            stat.get("total_rss"),
+            stat.get("total_cache"),
+            stat.get("total_shmem"),
+            stat.get("total_inactive_file"),
+            // present only with swap accounting
+            stat.get("total_swap").copied(),
+        ),
+        CgroupVersion::V2 => (
+            stat.get("anon"),
+            stat.get("file"),
+            stat.get("shmem"),
+            stat.get("inactive_file"),
+            read_value(&cgroup.file("memory", "memory.swap.current")).ok(),
+        ),
+    };
+    match mode {
+        MemoryMode::Auto | MemoryMode::Usage => Some(usage),
+        MemoryMode::WorkingSet => Some(usage.saturating_sub(*inactive_file?)),
+        MemoryMode::RssSwap => Some(anon? + swap.unwrap_or(0)),
+        MemoryMode::Available => {
+            // page cache except shared memory and reclaimable slabs could be freed
+            let reclaimable =
+                file?.saturating_sub(*shmem?) + stat.get("slab_reclaimable").copied().unwrap_or(0);
+            Some(usage.saturating_sub(reclaimable))
+        }
+    }
+}
+
+/// Build a measurement from used memory, peak is known only for raw usage
+fn cgroups_measurement(
+    cgroup: &Cgroup,
+    mode: MemoryMode,
+    usage: u64,
+    max_usage: u64,
+    limit: u64,
+) -> Option<MemoryMeasurement> {
+    let (used, max_used) = match mode {
+        MemoryMode::Auto | MemoryMode::Usage => (usage, max_usage),
+        _ => {
+            let used = cgroups_used(cgroup, mode, usage)?;
+            (used, used)
+        }
+    };
+    let utilization = (used as f64) / (limit as f64);
+    let max_utilization: f64 = (max_used as f64) / (limit as f64);
+    Some(MemoryMeasurement {
+        utilization,
+        max_utilization,
+        used,
+        limit,
+        max_used,
+    })
 }
 
 /// Detect system memory usage using cgroups v1
 /// Works only if memory limit is set (it is a case for Fargate containers)
-fn collect_memory_cgroups_v1() -> Option<MemoryMeasurement> {
-    if let Ok(usage) = read_cgroups_v1_usage() {
-        if let Ok(max_usage) = read_cgroups_v1_max_usage() {
-            if let Ok(limit) = read_cgroups_v1_limit() {
+fn collect_memory_cgroups_v1(cgroup: &Cgroup, mode: MemoryMode) -> Option<MemoryMeasurement> {
+    if let Ok(usage) = read_cgroups_v1_usage(cgroup) {
+        if let Ok(max_usage) = read_cgroups_v1_max_usage(cgroup) {
+            if let Ok(limit) = read_cgroups_v1_limit(cgroup) {
                 debug!(
                     "Got cgroups v1 memory usage {}, max {} and limit {}",
                     usage, max_usage, limit
                 );
-                let utilization = (usage as f64) / (limit as f64);
-                let max_utilization: f64 = (max_usage as f64) / (limit as f64);
-                return Some(MemoryMeasurement {
-                    utilization,
-                    max_utilization,
-                });
+                return cgroups_measurement(cgroup, mode, usage, max_usage, limit);
             }
         }
     }
     None
 }
 
+/// Detect system memory usage using cgroups v2
+/// Works only if memory limit is set, peak usage requires Linux 5.19
+fn collect_memory_cgroups_v2(cgroup: &Cgroup, mode: MemoryMode) -> Option<MemoryMeasurement> {
+    if let Ok(usage) = read_cgroups_v2_usage(cgroup) {
+        if let Ok(limit) = read_cgroups_v2_limit(cgroup) {
+            let max_usage = read_cgroups_v2_max_usage(cgroup).unwrap_or(usage);
+            debug!(
+                "Got cgroups v2 memory usage {}, max {} and limit {}",
+                usage, max_usage, limit
+            );
+            return cgroups_measurement(cgroup, mode, usage, max_usage, limit);
+        }
+    }
+    None
+}
+
+/// Parse /proc/meminfo content into values in bytes
+///
+/// Line format is:
+/// MemTotal:       16384256 kB
+fn parse_meminfo(content: &str) -> HashMap<String, u64> {
+    content
+        .lines()
+        .filter_map(|line| {
+            let (key, rest) = line.split_once(':')?;
+            let mut parts = rest.split_whitespace();
+            let value = parts.next()?.parse::<u64>().ok()?;
+            let multiplier = if parts.next() == Some("kB") { 1024 } else { 1 };
+            Some((key.to_string(), value * multiplier))
+        })
+        .collect()
+}
+
+/// Calculate used and total host memory according to the mode from /proc/meminfo
+fn meminfo_used(mode: MemoryMode) -> Option<(u64, u64)> {
+    let meminfo = parse_meminfo(&std::fs::read_to_string("/proc/meminfo").ok()?);
+    let total = *meminfo.get("MemTotal")?;
+    let used = match mode {
+        MemoryMode::Auto | MemoryMode::Available => {
+            total.saturating_sub(*meminfo.get("MemAvailable")?)
+        }
+        MemoryMode::Usage => total.saturating_sub(*meminfo.get("MemFree")?),
+        MemoryMode::WorkingSet => total
+            .saturating_sub(*meminfo.get("MemFree")?)
+            .saturating_sub(*meminfo.get("Inactive(file)")?),
+        MemoryMode::RssSwap => {
+            let swap = meminfo
+                .get("SwapTotal")?
+                .saturating_sub(*meminfo.get("SwapFree")?);
+            meminfo.get("AnonPages")? + swap
+        }
+    };
+    Some((used, total))
+}
+
 /// Detect system memory usage using a standard memory info
-fn collect_memory_sysinfo(sys: &mut System) -> MemoryMeasurement {
-    let total = sys.total_memory() as f64;
-    let utilization = (sys.used_memory() as f64) / total;
+fn collect_memory_sysinfo(sys: &mut System, mode: MemoryMode) -> MemoryMeasurement {
+    // sysinfo does not provide all values needed for explicit modes
+    let (used, total) = match mode {
+        MemoryMode::Auto => None,
+        _ => meminfo_used(mode),
+    }
+    .unwrap_or((sys.used_memory(), sys.total_memory()));
+    let utilization = (used as f64) / (total as f64);
     let max_utilization: f64 = utilization;
     MemoryMeasurement {
         utilization,
         max_utilization,
+        used,
+        limit: total,
+        max_used: used,
     }
 }
 
 /// Write memory info to writer
-pub fn collect_memory_info<W: std::fmt::Write>(f: &mut W, sys: &mut System) {
+pub fn collect_memory_info<W: std::fmt::Write>(
+    f: &mut W,
+    sys: &mut System,
+    cgroup: Option<&Cgroup>,
+    mode: MemoryMode,
+) {
     writeln!(
         f,
         "Sysinfo: used memory {}, system memory {}",
@@ -104,15 +381,253 @@ pub fn collect_memory_info<W: std::fmt::Write>(f: &mut W, sys: &mut System) {
         sys.total_memory()
     )
     .unwrap();
-    if let Ok(limit) = read_cgroups_v1_limit() {
-        writeln!(f, "cgroups v1: limit {}", limit).unwrap();
+    writeln!(
+        f,
+        "Sysinfo: used swap {}, system swap {}",
+        sys.used_swap(),
+        sys.total_swap()
+    )
+    .unwrap();
+    writeln!(f, "Memory utilization mode: {}", mode).unwrap();
+    match cgroup {
+        Some(cgroup) => {
+            writeln!(
+                f,
+                "cgroups: detected {} hierarchy at {}",
+                cgroup.version,
+                cgroup.path().display()
+            )
+            .unwrap();
+            let limit = match cgroup.version {
+                CgroupVersion::V1 => read_cgroups_v1_limit(cgroup),
+                CgroupVersion::V2 => read_cgroups_v2_limit(cgroup),
+            };
+            if let Ok(limit) = limit {
+                writeln!(f, "cgroups {}: limit {}", cgroup.version, limit).unwrap();
+            }
+        }
+        None => writeln!(f, "cgroups: not detected").unwrap(),
+    }
+}
+
+/// Sum memory events over measurements
+pub fn aggregate_memory_events(
+    series: &[&MemoryEventsMeasurement],
+) -> Option<MemoryEventsMeasurement> {
+    if series.is_empty() {
+        return None;
+    }
+    let sum = |field: fn(&MemoryEventsMeasurement) -> Option<u64>| {
+        series
+            .iter()
+            .filter_map(|events| field(events))
+            .reduce(|a, b| a + b)
+    };
+    Some(MemoryEventsMeasurement {
+        high: sum(|events| events.high),
+        max: sum(|events| events.max),
+        oom: sum(|events| events.oom),
+        oom_kill: sum(|events| events.oom_kill),
+    })
+}
+
+/// Detect swap usage using cgroups v1, requires swap accounting and a swap limit
+fn collect_swap_cgroups_v1(cgroup: &Cgroup, host_total: u64) -> Option<(u64, u64)> {
+    // memsw counters include both memory and swap
+    let memsw_usage = read_value(&cgroup.file("memory", "memory.memsw.usage_in_bytes")).ok()?;
+    let usage = read_cgroups_v1_usage(cgroup).ok()?;
+    let memsw_limit = read_limit(&cgroup.file("memory", "memory.memsw.limit_in_bytes")).ok()?;
+    let limit = read_limit(&cgroup.file("memory", "memory.limit_in_bytes")).ok()?;
+    // without a limit host swap is reported like host memory
+    let total = memsw_limit?.saturating_sub(limit?).min(host_total);
+    Some((memsw_usage.saturating_sub(usage), total))
+}
+
+/// Detect swap usage using cgroups v2, works only if a swap limit is set
+fn collect_swap_cgroups_v2(cgroup: &Cgroup, host_total: u64) -> Option<(u64, u64)> {
+    let used = read_value(&cgroup.file("memory", "memory.swap.current")).ok()?;
+    // without a limit host swap is reported like host memory
+    let limit = read_limit(&cgroup.file("memory", "memory.swap.max")).ok()??;
+    Some((used, limit.min(host_total)))
+}
+
+/// Detect swap usage, None if there is no swap
+pub fn collect_swap(sys: &mut System, cgroup: Option<&Cgroup>) -> Option<SwapMeasurement> {
+    let host_total = sys.total_swap();
+    let cgroup_swap = cgroup.and_then(|cgroup| match cgroup.version {
+        CgroupVersion::V1 => collect_swap_cgroups_v1(cgroup, host_total),
+        CgroupVersion::V2 => collect_swap_cgroups_v2(cgroup, host_total),
+    });
+    let (used, total) = cgroup_swap.unwrap_or((sys.used_swap(), host_total));
+    if total == 0 {
+        return None;
     }
+    Some(SwapMeasurement {
+        used,
+        max_used: used,
+        total,
+    })
+}
+
+/// Aggregate swap usage by median and max values
+pub fn aggregate_swap(series: &[&SwapMeasurement]) -> Option<SwapMeasurement> {
+    if series.is_empty() {
+        return None;
+    }
+    Some(SwapMeasurement {
+        used: median(series.iter().map(|swap| swap.used as f64).collect()) as u64,
+        max_used: series.iter().map(|swap| swap.max_used).max().unwrap_or(0),
+        total: series.iter().map(|swap| swap.total).max().unwrap_or(0),
+    })
+}
+
+/// Detect memory events since the previous measurement, available only for cgroups
+pub fn collect_memory_events(
+    cgroup: Option<&Cgroup>,
+    state: &mut MemoryState,
+) -> Option<MemoryEventsMeasurement> {
+    let cgroup = cgroup?;
+    let events = match cgroup.version {
+        CgroupVersion::V1 => read_cgroups_v1_events(cgroup),
+        CgroupVersion::V2 => read_cgroups_v2_events(cgroup),
+    }
+    .ok()?;
+    let last_events = state.last_events.replace(events.clone());
+    Some(events.delta(&last_events?))
+}
+
+/// Remember initial memory events so the first measurement has a baseline
+pub fn init_memory(state: &mut MemoryState, cgroup: Option<&Cgroup>) {
+    let _ = collect_memory_events(cgroup, state);
 }
 
 /// Detect system memory usage
-pub fn collect_memory(sys: &mut System) -> MemoryMeasurement {
-    if let Some(mem) = collect_memory_cgroups_v1() {
+pub fn collect_memory(
+    sys: &mut System,
+    cgroup: Option<&Cgroup>,
+    mode: MemoryMode,
+) -> MemoryMeasurement {
+    let cgroup_measurement = cgroup.and_then(|cgroup| match cgroup.version {
+        CgroupVersion::V1 => collect_memory_cgroups_v1(cgroup, mode),
+        CgroupVersion::V2 => collect_memory_cgroups_v2(cgroup, mode),
+    });
+    if let Some(mem) = cgroup_measurement {
         return mem;
     }
-    collect_memory_sysinfo(sys)
+    collect_memory_sysinfo(sys, mode)
+}
+
+/// Tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_collect_memory_cgroups_v2() {
+        let root = create_fixture(
+            "memory-v2",
+            &[
+                ("proc/cgroup", "0::/\n"),
+                ("mnt/cgroup.controllers", "cpu memory\n"),
+                ("mnt/memory.current", "256\n"),
+                ("mnt/memory.max", "1024\n"),
+                ("mnt/memory.peak", "512\n"),
+            ],
+        );
+        let cgroup = Cgroup::detect_at(&root.join("mnt"), &root.join("proc/cgroup")).unwrap();
+        let mem = collect_memory_cgroups_v2(&cgroup, MemoryMode::Auto).unwrap();
+        assert!((mem.utilization - 0.25).abs() < 0.001);
+        assert!((mem.max_utilization - 0.5).abs() < 0.001);
+        assert_eq!((mem.used, mem.limit, mem.max_used), (256, 1024, 512));
+
+        std::fs::write(
+            root.join("mnt/memory.stat"),
+            "anon 100\nfile 150\nshmem 10\nslab 6\nactive_file 50\ninactive_file 100\n",
+        )
+        .unwrap();
+        assert_eq!(
+            collect_memory_breakdown(Some(&cgroup)).unwrap(),
+            MemoryBreakdown {
+                anon: 100,
+                file: 150,
+                shmem: 10,
+                slab: Some(6),
+                working_set: 156,
+            }
+        );
+
+        std::fs::write(root.join("mnt/memory.max"), "1024\n").unwrap();
+        std::fs::write(root.join("mnt/memory.swap.current"), "28\n").unwrap();
+        let mem = collect_memory_cgroups_v2(&cgroup, MemoryMode::WorkingSet).unwrap();
+        assert_eq!((mem.used, mem.max_used), (156, 156));
+        let mem = collect_memory_cgroups_v2(&cgroup, MemoryMode::RssSwap).unwrap();
+        assert_eq!(mem.used, 128);
+        let mem = collect_memory_cgroups_v2(&cgroup, MemoryMode::Available).unwrap();
+        assert_eq!(mem.used, 116);
+
+        std::fs::write(root.join("mnt/memory.max"), "max\n").unwrap();
+        assert!(collect_memory_cgroups_v2(&cgroup, MemoryMode::Auto).is_none());
+    }
+
+    #[test]
+    fn test_collect_swap_cgroups_v2() {
+        let root = create_fixture(
+            "swap-v2",
+            &[
+                ("proc/cgroup", "0::/\n"),
+                ("mnt/cgroup.controllers", "cpu memory\n"),
+                ("mnt/memory.swap.current", "256\n"),
+                ("mnt/memory.swap.max", "max\n"),
+            ],
+        );
+        let cgroup = Cgroup::detect_at(&root.join("mnt"), &root.join("proc/cgroup")).unwrap();
+        // no swap limit means a service of the host, host swap is used
+        assert_eq!(collect_swap_cgroups_v2(&cgroup, 2048), None);
+        std::fs::write(root.join("mnt/memory.swap.max"), "1024\n").unwrap();
+        assert_eq!(collect_swap_cgroups_v2(&cgroup, 2048), Some((256, 1024)));
+        std::fs::write(root.join("mnt/memory.swap.max"), "4096\n").unwrap();
+        assert_eq!(collect_swap_cgroups_v2(&cgroup, 2048), Some((256, 2048)));
+    }
+
+    #[test]
+    fn test_parse_meminfo() {
+        let meminfo = parse_meminfo("MemTotal:       2048 kB\nHugePages_Total:       0\n");
+        assert_eq!(meminfo["MemTotal"], 2048 * 1024);
+        assert_eq!(meminfo["HugePages_Total"], 0);
+    }
+
+    #[test]
+    fn test_collect_memory_events_cgroups_v1() {
+        let root = create_fixture(
+            "memory-events-v1",
+            &[
+                ("proc/cgroup", "4:memory:/\n"),
+                ("mnt/memory/memory.failcnt", "3\n"),
+                (
+                    "mnt/memory/memory.oom_control",
+                    "oom_kill_disable 0\nunder_oom 0\noom_kill 1\n",
+                ),
+            ],
+        );
+        let cgroup = Cgroup::detect_at(&root.join("mnt"), &root.join("proc/cgroup")).unwrap();
+        let mut state = MemoryState::default();
+        init_memory(&mut state, Some(&cgroup));
+
+        std::fs::write(root.join("mnt/memory/memory.failcnt"), "5\n").unwrap();
+        std::fs::write(
+            root.join("mnt/memory/memory.oom_control"),
+            "oom_kill_disable 0\nunder_oom 0\noom_kill 2\n",
+        )
+        .unwrap();
+        let events = collect_memory_events(Some(&cgroup), &mut state).unwrap();
+        assert_eq!(
+            events,
+            MemoryEventsMeasurement {
+                high: None,
+                max: Some(2),
+                oom: None,
+                oom_kill: Some(1),
+            }
+        );
+    }
 }
@@ -1,42 +1,287 @@
+use crate::cgroups::Cgroup;
+use crate::config::{CollectorConfig, MemoryMode, ProcessSelector};
+use crate::cpu::*;
+use crate::custom::CustomMetric;
+use crate::disk::*;
+use crate::diskio::*;
+use crate::files::*;
+use crate::load::*;
 use crate::memory::*;
+use crate::network::*;
+use crate::pids::*;
+use crate::pressure::*;
+use crate::process::*;
+use crate::sockets::*;
+use crate::steal::*;
+use crate::vmstat::*;
 
 use chrono::{DateTime, Utc};
 use log::*;
 use rstats::triangmat::Vecops;
 use rstats::Medianf64;
 use std::fmt;
-use std::time::SystemTime;
-use sysinfo::{CpuExt, CpuRefreshKind, ProcessRefreshKind, RefreshKind, System, SystemExt};
+use std::time::{Duration, SystemTime};
+use sysinfo::{CpuRefreshKind, ProcessRefreshKind, RefreshKind, System, SystemExt};
 
 pub struct Measurement {
     pub timestamp: SystemTime,
     pub mem_utilization: f64,
     pub max_mem_utilization: f64,
+    pub mem_used: u64,
+    pub mem_limit: u64,
+    pub max_mem_used: u64,
+    pub mem_breakdown: Option<MemoryBreakdown>,
+    pub swap: Option<SwapMeasurement>,
     pub cpu_utilization: f64,
+    pub cpu_times: Option<CpuTimes>,
+    pub steal_sustained: Option<bool>,
+    pub cores: Vec<CoreMeasurement>,
+    pub cpu_throttling: Option<ThrottlingMeasurement>,
+    pub load: Option<LoadMeasurement>,
+    pub pressure: Option<PressureMeasurement>,
+    pub memory_events: Option<MemoryEventsMeasurement>,
+    pub vmstat: Option<VmstatMeasurement>,
+    pub pids: Option<PidsMeasurement>,
+    pub files: Option<FilesMeasurement>,
+    pub processes: Vec<ProcessMeasurement>,
+    pub top_processes: Option<TopProcesses>,
+    pub disks: Vec<DiskMeasurement>,
+    pub io: Option<IoMeasurement>,
+    pub network: Vec<NetworkMeasurement>,
+    pub sockets: Option<SocketsMeasurement>,
+    /// Metrics received from applications during the period
+    pub custom: Vec<CustomMetric>,
     pub sample_count: u32,
 }
 
+impl Default for Measurement {
+    fn default() -> Self {
+        Measurement {
+            timestamp: SystemTime::now(),
+            mem_utilization: 0.0,
+            max_mem_utilization: 0.0,
+            mem_used: 0,
+            mem_limit: 0,
+            max_mem_used: 0,
+            mem_breakdown: None,
+            swap: None,
+            cpu_utilization: 0.0,
+            cpu_times: None,
+            steal_sustained: None,
+            cores: vec![],
+            cpu_throttling: None,
+            load: None,
+            pressure: None,
+            memory_events: None,
+            vmstat: None,
+            pids: None,
+            files: None,
+            processes: vec![],
+            top_processes: None,
+            disks: vec![],
+            io: None,
+            network: vec![],
+            sockets: None,
+            custom: vec![],
+            sample_count: 1,
+        }
+    }
+}
+
+impl Measurement {
+    /// Utilization of the busiest core, None if per-core metrics are disabled
+    pub fn max_core_utilization(&self) -> Option<f64> {
+        self.cores.iter().map(|c| c.utilization).reduce(f64::max)
+    }
+}
+
 impl fmt::Debug for Measurement {
     fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
         let dt: DateTime<Utc> = self.timestamp.into();
         write!(
             fmt,
-            "Measurement {{ ts {}, cpu {:.3}, mem {:.3}, maxmem {:.3} }}",
+            "Measurement {{ ts {}, cpu {:.3}, mem {:.3}, maxmem {:.3}, memused {}/{}",
             dt.to_rfc3339(),
             self.cpu_utilization,
             self.mem_utilization,
-            self.max_mem_utilization
+            self.max_mem_utilization,
+            self.mem_used,
+            self.mem_limit
         )?;
+        if let Some(breakdown) = &self.mem_breakdown {
+            write!(fmt, ", workingset {}", breakdown.working_set)?;
+        }
+        if let Some(swap) = &self.swap {
+            write!(fmt, ", swap {:.3}", swap.utilization())?;
+        }
+        if let Some(max_core) = self.max_core_utilization() {
+            write!(fmt, ", maxcore {:.3}", max_core)?;
+        }
+        if let Some(times) = &self.cpu_times {
+            write!(
+                fmt,
+                ", iowait {:.3}, steal {:.3}",
+                times.ratio(times.iowait),
+                times.ratio(times.steal)
+            )?;
+        }
+        if self.steal_sustained == Some(true) {
+            write!(fmt, ", steal sustained")?;
+        }
+        if let Some(throttling) = &self.cpu_throttling {
+            write!(
+                fmt,
+                ", throttled {:.3} for {} us",
+                throttling.throttled_ratio(),
+                throttling.throttled_usec
+            )?;
+        }
+        if let Some(load) = &self.load {
+            write!(fmt, ", load {:.3}", load.load1)?;
+        }
+        if let Some(pressure) = &self.pressure {
+            for (name, values) in pressure.resources() {
+                if let Some(values) = values {
+                    write!(fmt, ", {}psi {:.3}", name.to_lowercase(), values.some_avg10)?;
+                }
+            }
+        }
+        if let Some(events) = &self.memory_events {
+            write!(fmt, ", oomkill {}", events.oom_kill.unwrap_or(0))?;
+        }
+        if let Some(vmstat) = &self.vmstat {
+            write!(
+                fmt,
+                ", majflt {:.1}/s",
+                vmstat.rate(vmstat.counters.major_page_faults)
+            )?;
+        }
+        if let Some(pids) = &self.pids {
+            write!(fmt, ", procs {}/{}", pids.processes, pids.threads)?;
+        }
+        if let Some(files) = &self.files {
+            write!(fmt, ", files {}", files.allocated)?;
+        }
+        for process in &self.processes {
+            write!(
+                fmt,
+                ", process {} cpu {:.3} rss {}",
+                process.label, process.cpu_utilization, process.rss
+            )?;
+        }
+        if let Some(top) = &self.top_processes {
+            if let Some(usage) = top.by_cpu.first() {
+                write!(fmt, ", topcpu {} {:.3}", usage.name, usage.cpu_utilization)?;
+            }
+            if let Some(usage) = top.by_rss.first() {
+                write!(fmt, ", toprss {} {}", usage.name, usage.rss)?;
+            }
+        }
+        for disk in &self.disks {
+            write!(fmt, ", disk {} {:.3}", disk.mount_path, disk.utilization())?;
+        }
+        if let Some(io) = &self.io {
+            write!(
+                fmt,
+                ", io r/w {:.0}/{:.0} B/s",
+                io.rate(io.counters.read_bytes),
+                io.rate(io.counters.write_bytes)
+            )?;
+        }
+        for network in &self.network {
+            write!(
+                fmt,
+                ", net {} rx/tx {:.0}/{:.0} B/s",
+                network.interface.as_deref().unwrap_or("total"),
+                network.rate(network.counters.rx_bytes),
+                network.rate(network.counters.tx_bytes)
+            )?;
+        }
+        if let Some(sockets) = &self.sockets {
+            write!(
+                fmt,
+                ", tcp estab {} closewait {}",
+                sockets.established, sockets.close_wait
+            )?;
+        }
+        if !self.custom.is_empty() {
+            write!(fmt, ", custom {}", self.custom.len())?;
+        }
+        write!(fmt, " }}")?;
         Ok(())
     }
 }
 
-pub fn create_measurement_engine() -> System {
+/// Sources of measurements kept between samples
+pub struct MeasurementEngine {
+    sys: System,
+    cgroup: Option<Cgroup>,
+    cpu_state: CpuState,
+    pressure_state: PressureState,
+    memory_state: MemoryState,
+    io_state: IoState,
+    network_state: NetworkState,
+    vmstat_state: VmstatState,
+    process_state: ProcessState,
+    steal_state: StealState,
+    per_core: bool,
+    memory_mode: MemoryMode,
+    mount_points: Vec<String>,
+    sum_interfaces: bool,
+    tcp_ports: Vec<u16>,
+    processes: Vec<ProcessSelector>,
+    top_processes: Option<usize>,
+}
+
+pub fn create_measurement_engine(config: &CollectorConfig) -> MeasurementEngine {
     let refresh_kind = RefreshKind::new()
         .with_cpu(CpuRefreshKind::new().with_cpu_usage())
         .with_memory()
         .with_processes(ProcessRefreshKind::everything());
-    System::new_with_specifics(refresh_kind)
+    let cgroup = Cgroup::detect();
+    let mut cpu_state = CpuState::default();
+    init_cpu(&mut cpu_state, cgroup.as_ref());
+    let mut pressure_state = PressureState::default();
+    init_pressure(&mut pressure_state, cgroup.as_ref());
+    let mut memory_state = MemoryState::default();
+    init_memory(&mut memory_state, cgroup.as_ref());
+    let mut io_state = IoState::default();
+    init_io(&mut io_state, cgroup.as_ref());
+    let mut network_state = NetworkState::default();
+    init_network(&mut network_state);
+    let mut vmstat_state = VmstatState::default();
+    init_vmstat(&mut vmstat_state, cgroup.as_ref());
+    MeasurementEngine {
+        sys: System::new_with_specifics(refresh_kind),
+        cgroup,
+        cpu_state,
+        pressure_state,
+        memory_state,
+        io_state,
+        network_state,
+        vmstat_state,
+        process_state: ProcessState::default(),
+        steal_state: StealState::new(
+            Duration::from_secs(config.steal_window as u64),
+            config.steal_threshold,
+        ),
+        per_core: config.per_core,
+        memory_mode: config.memory_mode,
+        mount_points: if config.mount_points.is_empty() {
+            default_mount_points()
+        } else {
+            config.mount_points.clone()
+        },
+        sum_interfaces: config.sum_interfaces,
+        tcp_ports: config.tcp_ports.clone(),
+        processes: config.processes.clone(),
+        top_processes: config.top_processes,
+    }
+}
+
+/// Median of values, zero for an empty list
+pub(crate) fn median(values: Vec<f64>) -> f64 {
+    values.median().unwrap_or(0.0)
 }
 
 fn nan_to_zero(value: f64) -> f64 {
@@ -47,30 +292,60 @@ fn nan_to_zero(value: f64) -> f64 {
     }
 }
 
-pub fn create_measurement(sys: &mut System) -> Measurement {
+pub fn create_measurement(engine: &mut MeasurementEngine) -> Measurement {
+    let sys = &mut engine.sys;
     sys.refresh_cpu();
     sys.refresh_memory();
-    //for p in sys.cpus() {
-    //println!(" cpu {}", p.cpu_usage());
-    //}
-    //println!();
-
-    let cpu_count = sys.cpus().len();
-    let cpu_sum: f64 = sys.cpus().iter().map(|p| p.cpu_usage() as f64).sum();
-    let cpu_avg = if cpu_count > 0 && !cpu_sum.is_nan() {
-        cpu_sum / (cpu_count as f64) / 100.0
+    if !engine.processes.is_empty() || engine.top_processes.is_some() {
+        sys.refresh_processes();
+    }
+
+    let cpu_utilization = collect_cpu(sys, engine.cgroup.as_ref(), &mut engine.cpu_state);
+    let cpu_times = collect_cpu_times(&mut engine.cpu_state);
+    let steal_sustained = track_steal(&mut engine.steal_state, cpu_times.as_ref());
+    let cores = if engine.per_core {
+        collect_cores(sys)
     } else {
-        0.0
+        vec![]
     };
-    let cpu_utilization: f64 = cpu_avg;
+    let cpu_throttling = collect_throttling(engine.cgroup.as_ref(), &mut engine.cpu_state);
+    let load = collect_load(sys, engine.cgroup.as_ref());
+    let pressure = collect_pressure(engine.cgroup.as_ref(), &mut engine.pressure_state);
 
-    let memory_measurement = collect_memory(sys);
+    let memory_measurement = collect_memory(sys, engine.cgroup.as_ref(), engine.memory_mode);
+    let mem_breakdown = collect_memory_breakdown(engine.cgroup.as_ref());
+    let swap = collect_swap(sys, engine.cgroup.as_ref());
+    let memory_events = collect_memory_events(engine.cgroup.as_ref(), &mut engine.memory_state);
 
     Measurement {
         timestamp: SystemTime::now(),
         cpu_utilization,
         mem_utilization: nan_to_zero(memory_measurement.utilization),
         max_mem_utilization: nan_to_zero(memory_measurement.max_utilization),
+        mem_used: memory_measurement.used,
+        mem_limit: memory_measurement.limit,
+        max_mem_used: memory_measurement.max_used,
+        mem_breakdown,
+        swap,
+        cpu_times,
+        steal_sustained,
+        cores,
+        cpu_throttling,
+        load,
+        pressure,
+        memory_events,
+        vmstat: collect_vmstat(engine.cgroup.as_ref(), &mut engine.vmstat_state),
+        pids: collect_pids(engine.cgroup.as_ref()),
+        files: collect_files(),
+        processes: collect_processes(sys, &engine.processes, &mut engine.process_state),
+        top_processes: engine
+            .top_processes
+            .map(|count| collect_top_processes(sys, count)),
+        disks: collect_disks(&engine.mount_points),
+        io: collect_io(engine.cgroup.as_ref(), &mut engine.io_state),
+        network: collect_network(&mut engine.network_state, engine.sum_interfaces),
+        sockets: collect_sockets(&engine.tcp_ports),
+        custom: vec![],
         sample_count: 1,
     }
 }
@@ -98,21 +373,165 @@ pub fn aggregate(series: &[Measurement]) -> Option<Measurement> {
         .collect::<Vec<f64>>()
         .minmax()
         .max;
+    let avg_mem_used = median(series.iter().map(|m| m.mem_used as f64).collect()) as u64;
+    let mem_limit = series.iter().map(|m| m.mem_limit).max().unwrap_or(0);
+    let max_mem_used = series.iter().map(|m| m.max_mem_used).max().unwrap_or(0);
+    let mem_breakdown = aggregate_memory_breakdown(
+        &series
+            .iter()
+            .filter_map(|m| m.mem_breakdown.as_ref())
+            .collect::<Vec<_>>(),
+    );
+    let swap = aggregate_swap(
+        &series
+            .iter()
+            .filter_map(|m| m.swap.as_ref())
+            .collect::<Vec<_>>(),
+    );
+    let cpu_times = aggregate_cpu_times(
+        &series
+            .iter()
+            .filter_map(|m| m.cpu_times.as_ref())
+            .collect::<Vec<_>>(),
+    );
+    let steal_sustained = aggregate_steal_sustained(
+        &series
+            .iter()
+            .filter_map(|m| m.steal_sustained)
+            .collect::<Vec<_>>(),
+    );
+    let cores = aggregate_cores(
+        &series
+            .iter()
+            .map(|m| m.cores.as_slice())
+            .collect::<Vec<_>>(),
+    );
+    let cpu_throttling = aggregate_throttling(
+        &series
+            .iter()
+            .filter_map(|m| m.cpu_throttling.as_ref())
+            .collect::<Vec<_>>(),
+    );
+    let load = aggregate_load(
+        &series
+            .iter()
+            .filter_map(|m| m.load.as_ref())
+            .collect::<Vec<_>>(),
+    );
+    let pressure = aggregate_pressure(
+        &series
+            .iter()
+            .filter_map(|m| m.pressure.as_ref())
+            .collect::<Vec<_>>(),
+    );
+    let memory_events = aggregate_memory_events(
+        &series
+            .iter()
+            .filter_map(|m| m.memory_events.as_ref())
+            .collect::<Vec<_>>(),
+    );
+    let vmstat = aggregate_vmstat(
+        &series
+            .iter()
+            .filter_map(|m| m.vmstat.as_ref())
+            .collect::<Vec<_>>(),
+    );
+    let pids = aggregate_pids(
+        &series
+            .iter()
+            .filter_map(|m| m.pids.as_ref())
+            .collect::<Vec<_>>(),
+    );
+    let files = aggregate_files(
+        &series
+            .iter()
+            .filter_map(|m| m.files.as_ref())
+            .collect::<Vec<_>>(),
+    );
+    let processes = aggregate_processes(
+        &series
+            .iter()
+            .map(|m| m.processes.as_slice())
+            .collect::<Vec<_>>(),
+    );
+    let top_processes = aggregate_top_processes(
+        &series
+            .iter()
+            .filter_map(|m| m.top_processes.as_ref())
+            .collect::<Vec<_>>(),
+    );
+    let disks = aggregate_disks(
+        &series
+            .iter()
+            .map(|m| m.disks.as_slice())
+            .collect::<Vec<_>>(),
+    );
+    let io = aggregate_io(
+        &series
+            .iter()
+            .filter_map(|m| m.io.as_ref())
+            .collect::<Vec<_>>(),
+    );
+    let network = aggregate_network(
+        &series
+            .iter()
+            .map(|m| m.network.as_slice())
+            .collect::<Vec<_>>(),
+    );
+    let sockets = aggregate_sockets(
+        &series
+            .iter()
+            .filter_map(|m| m.sockets.as_ref())
+            .collect::<Vec<_>>(),
+    );
     Some(Measurement {
         timestamp: series[series.len() - 1].timestamp,
         cpu_utilization: avg_cpu,
         mem_utilization: avg_mem,
         max_mem_utilization: max_mem,
+        mem_used: avg_mem_used,
+        mem_limit,
+        max_mem_used,
+        mem_breakdown,
+        swap,
+        cpu_times,
+        steal_sustained,
+        cores,
+        cpu_throttling,
+        load,
+        pressure,
+        memory_events,
+        vmstat,
+        pids,
+        files,
+        processes,
+        top_processes,
+        disks,
+        io,
+        network,
+        sockets,
+        custom: vec![],
         sample_count: series.len() as u32,
     })
 }
 
 /// Write generic system info into writer
-pub fn collect_info<W: std::fmt::Write>(f: &mut W, sys: &mut System) {
+pub fn collect_info<W: std::fmt::Write>(f: &mut W, engine: &mut MeasurementEngine) {
+    let sys = &mut engine.sys;
     sys.refresh_cpu();
     sys.refresh_memory();
-    collect_memory_info(f, sys);
-    writeln!(f, "Sysinfo: cpu count: {}", sys.cpus().len()).unwrap();
+    collect_memory_info(f, sys, engine.cgroup.as_ref(), engine.memory_mode);
+    collect_cpu_info(f, sys, engine.cgroup.as_ref());
+    collect_steal_info(f, &engine.steal_state);
+    collect_load_info(f, sys, engine.cgroup.as_ref());
+    collect_pressure_info(f, engine.cgroup.as_ref());
+    collect_pids_info(f, engine.cgroup.as_ref());
+    collect_files_info(f);
+    collect_processes_info(f, sys, &engine.processes);
+    collect_disk_info(f, &engine.mount_points);
+    collect_io_info(f, engine.cgroup.as_ref());
+    collect_network_info(f, engine.sum_interfaces);
+    collect_sockets_info(f, &engine.tcp_ports);
 }
 
 /// Tests
@@ -125,7 +544,7 @@ mod tests {
 
     #[test]
     fn test_measurement() {
-        let mut engine = create_measurement_engine();
+        let mut engine = create_measurement_engine(&CollectorConfig::default());
         let measurement = create_measurement(&mut engine);
         assert!(!measurement.cpu_utilization.is_nan());
         assert!(!measurement.mem_utilization.is_nan());
@@ -136,7 +555,7 @@ mod tests {
 
     #[test]
     fn test_measurement_times() {
-        let mut engine = create_measurement_engine();
+        let mut engine = create_measurement_engine(&CollectorConfig::default());
         for _ in 0..10 {
             let measurement = create_measurement(&mut engine);
             println!("{:?}", measurement);
@@ -167,7 +586,15 @@ mod tests {
                     cpu_utilization: k as f64 * 0.05,
                     mem_utilization: k as f64 * 0.07,
                     max_mem_utilization: k as f64 * 0.07,
-                    sample_count: 1,
+                    mem_used: k * 100,
+                    mem_limit: 1000,
+                    max_mem_used: k * 100,
+                    cpu_throttling: Some(ThrottlingMeasurement {
+                        periods: 10,
+                        throttled_periods: k,
+                        throttled_usec: 100,
+                    }),
+                    ..Default::default()
                 }
             })
             .collect();
@@ -178,5 +605,12 @@ mod tests {
         assert!((agg.cpu_utilization - (0.05 / 2.0 * ((n - 1) as f64))).abs() < 0.001);
         assert!((agg.mem_utilization - (0.07 / 2.0 * ((n - 1) as f64))).abs() < 0.001);
         assert!((agg.max_mem_utilization - (0.07 * (n - 1) as f64)).abs() < 0.001);
+        assert_eq!(agg.mem_used, 50 * (n - 1));
+        assert_eq!(agg.mem_limit, 1000);
+        assert_eq!(agg.max_mem_used, 100 * (n - 1));
+        let throttling = agg.cpu_throttling.unwrap();
+        assert_eq!(throttling.periods, 10 * n);
+        assert_eq!(throttling.throttled_periods, n * (n - 1) / 2);
+        assert_eq!(throttling.throttled_usec, 100 * n);
     }
 }
@@ -0,0 +1,243 @@
+use log::debug;
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+/// Interface statistics of the current network namespace
+const PROC_NET_DEV: &str = "/proc/net/dev";
+
+/// Loopback traffic never leaves the host
+const LOOPBACK_INTERFACE: &str = "lo";
+
+/// Network interface counters
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct NetworkCounters {
+    pub rx_bytes: u64,
+    pub tx_bytes: u64,
+    pub rx_packets: u64,
+    pub tx_packets: u64,
+    pub rx_errors: u64,
+    pub tx_errors: u64,
+    pub rx_dropped: u64,
+    pub tx_dropped: u64,
+}
+
+impl NetworkCounters {
+    /// Counters increment since the previous cumulative value
+    fn delta(&self, prev: &NetworkCounters) -> NetworkCounters {
+        NetworkCounters {
+            rx_bytes: self.rx_bytes.saturating_sub(prev.rx_bytes),
+            tx_bytes: self.tx_bytes.saturating_sub(prev.tx_bytes),
+            rx_packets: self.rx_packets.saturating_sub(prev.rx_packets),
+            tx_packets: self.tx_packets.saturating_sub(prev.tx_packets),
+            rx_errors: self.rx_errors.saturating_sub(prev.rx_errors),
+            tx_errors: self.tx_errors.saturating_sub(prev.tx_errors),
+            rx_dropped: self.rx_dropped.saturating_sub(prev.rx_dropped),
+            tx_dropped: self.tx_dropped.saturating_sub(prev.tx_dropped),
+        }
+    }
+
+    fn add(&mut self, other: &NetworkCounters) {
+        self.rx_bytes += other.rx_bytes;
+        self.tx_bytes += other.tx_bytes;
+        self.rx_packets += other.rx_packets;
+        self.tx_packets += other.tx_packets;
+        self.rx_errors += other.rx_errors;
+        self.tx_errors += other.tx_errors;
+        self.rx_dropped += other.rx_dropped;
+        self.tx_dropped += other.tx_dropped;
+    }
+}
+
+/// Network traffic of an interface during a time interval
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct NetworkMeasurement {
+    /// Interface name, none for a sum of all non-loopback interfaces
+    pub interface: Option<String>,
+    pub counters: NetworkCounters,
+    pub elapsed: Duration,
+}
+
+impl NetworkMeasurement {
+    /// Convert a counter to a per-second rate
+    pub fn rate(&self, value: u64) -> f64 {
+        let seconds = self.elapsed.as_secs_f64();
+        if seconds > 0.0 {
+            value as f64 / seconds
+        } else {
+            0.0
+        }
+    }
+}
+
+/// Network counters observed at the previous measurement
+#[derive(Default)]
+pub struct NetworkState {
+    last: Option<(HashMap<String, NetworkCounters>, Instant)>,
+}
+
+/// Parse /proc/net/dev content into counters of non-loopback interfaces
+///
+/// Line format after two header lines is:
+/// eth0: rx_bytes packets errs drop fifo frame compressed multicast tx_bytes packets errs drop ...
+fn parse_net_dev(content: &str) -> HashMap<String, NetworkCounters> {
+    content
+        .lines()
+        .filter_map(|line| {
+            let (interface, values) = line.split_once(':')?;
+            let interface = interface.trim();
+            if interface == LOOPBACK_INTERFACE {
+                return None;
+            }
+            let values: Vec<u64> = values
+                .split_whitespace()
+                .map(|value| value.parse::<u64>())
+                .collect::<Result<_, _>>()
+                .ok()?;
+            if values.len() < 12 {
+                return None;
+            }
+            let counters = NetworkCounters {
+                rx_bytes: values[0],
+                rx_packets: values[1],
+                rx_errors: values[2],
+                rx_dropped: values[3],
+                tx_bytes: values[8],
+                tx_packets: values[9],
+                tx_errors: values[10],
+                tx_dropped: values[11],
+            };
+            Some((interface.to_string(), counters))
+        })
+        .collect()
+}
+
+/// Sum traffic of each interface over measurements
+pub fn aggregate_network(series: &[&[NetworkMeasurement]]) -> Vec<NetworkMeasurement> {
+    let mut networks: Vec<NetworkMeasurement> = vec![];
+    for network in series.iter().flat_map(|networks| networks.iter()) {
+        match networks
+            .iter_mut()
+            .find(|n| n.interface == network.interface)
+        {
+            Some(existing) => {
+                existing.counters.add(&network.counters);
+                existing.elapsed += network.elapsed;
+            }
+            None => networks.push(network.clone()),
+        }
+    }
+    networks
+}
+
+/// Write network info to writer
+pub fn collect_network_info<W: std::fmt::Write>(f: &mut W, sum_interfaces: bool) {
+    match std::fs::read_to_string(PROC_NET_DEV) {
+        Ok(content) => {
+            let mut interfaces: Vec<String> = parse_net_dev(&content).into_keys().collect();
+            interfaces.sort();
+            writeln!(
+                f,
+                "Network: interfaces {}{}",
+                interfaces.join(", "),
+                if sum_interfaces { " (summed)" } else { "" }
+            )
+            .unwrap()
+        }
+        Err(err) => writeln!(f, "Network: not available: {}", err).unwrap(),
+    }
+}
+
+/// Detect network traffic since the previous measurement
+pub fn collect_network(state: &mut NetworkState, sum_interfaces: bool) -> Vec<NetworkMeasurement> {
+    let counters = match std::fs::read_to_string(PROC_NET_DEV) {
+        Ok(content) => parse_net_dev(&content),
+        Err(err) => {
+            debug!("Cannot read network counters: {}", err);
+            return vec![];
+        }
+    };
+    let now = Instant::now();
+    let Some((prev_counters, prev_time)) = state.last.replace((counters.clone(), now)) else {
+        return vec![];
+    };
+    let elapsed = now.duration_since(prev_time);
+    // interfaces that appeared since the previous measurement have no baseline yet
+    let mut networks: Vec<NetworkMeasurement> = counters
+        .iter()
+        .filter_map(|(interface, counters)| {
+            Some(NetworkMeasurement {
+                interface: Some(interface.clone()),
+                counters: counters.delta(prev_counters.get(interface)?),
+                elapsed,
+            })
+        })
+        .collect();
+    if sum_interfaces {
+        let mut total = NetworkCounters::default();
+        for network in &networks {
+            total.add(&network.counters);
+        }
+        return vec![NetworkMeasurement {
+            interface: None,
+            counters: total,
+            elapsed,
+        }];
+    }
+    networks.sort_by(|a, b| a.interface.cmp(&b.interface));
+    networks
+}
+
+/// Remember initial network counters so the first measurement has a baseline
+pub fn init_network(state: &mut NetworkState) {
+    let _ = collect_network(state, false);
+}
+
+/// Tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_net_dev() {
+        let content = "\
+Inter-|   Receive                                                |  Transmit
+ face |bytes    packets errs drop fifo frame compressed multicast|bytes    packets errs drop fifo colls carrier compressed
+    lo: 2382 48 0 0 0 0 0 0 2382 48 0 0 0 0 0 0
+  eth0: 21735 1670 1 2 0 0 0 0 1470 1800 3 4 0 0 0 0
+";
+        let counters = parse_net_dev(content);
+        assert_eq!(counters.len(), 1);
+        assert_eq!(
+            counters["eth0"],
+            NetworkCounters {
+                rx_bytes: 21735,
+                tx_bytes: 1470,
+                rx_packets: 1670,
+                tx_packets: 1800,
+                rx_errors: 1,
+                tx_errors: 3,
+                rx_dropped: 2,
+                tx_dropped: 4,
+            }
+        );
+    }
+
+    #[test]
+    fn test_aggregate_network() {
+        let sample = |interface: &str, rx_bytes: u64| NetworkMeasurement {
+            interface: Some(interface.to_string()),
+            counters: NetworkCounters {
+                rx_bytes,
+                ..Default::default()
+            },
+            elapsed: Duration::from_secs(10),
+        };
+        let first = [sample("eth0", 100), sample("eth1", 10)];
+        let second = [sample("eth0", 300)];
+        let networks = aggregate_network(&[&first[..], &second[..]]);
+        assert_eq!(networks.len(), 2);
+        assert_eq!(networks[0].counters.rx_bytes, 400);
+        assert!((networks[0].rate(networks[0].counters.rx_bytes) - 20.0).abs() < 0.0001);
+        assert_eq!(networks[1].elapsed, Duration::from_secs(10));
+    }
+}
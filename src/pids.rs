@@ -0,0 +1,166 @@
+use crate::cgroups::*;
+use crate::metrics::median;
+
+use log::debug;
+use std::path::Path;
+
+/// Process filesystem of the agent PID namespace
+const PROC: &str = "/proc";
+
+/// Process and thread counts with the cgroup pids limit
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct PidsMeasurement {
+    /// Number of processes in the cgroup or visible in the PID namespace
+    pub processes: u64,
+    /// Number of threads in the cgroup or visible in the PID namespace
+    pub threads: u64,
+    /// Number of tasks in the cgroup, None without pids controller
+    pub pids_current: Option<u64>,
+    /// Maximum number of tasks in the cgroup, None if no limit is imposed
+    pub pids_limit: Option<u64>,
+}
+
+impl PidsMeasurement {
+    /// Share of the pids limit in use, None if no limit is imposed
+    pub fn utilization(&self) -> Option<f64> {
+        let limit = self.pids_limit.filter(|&limit| limit > 0)?;
+        let current = self.pids_current.unwrap_or(self.threads);
+        Some(current as f64 / limit as f64)
+    }
+}
+
+/// Read current and maximum number of tasks of the cgroup
+fn read_cgroups_pids(cgroup: &Cgroup) -> Result<(u64, Option<u64>), Box<dyn std::error::Error>> {
+    let current = read_value(&cgroup.file("pids", "pids.current"))?;
+    let limit = read_limit(&cgroup.file("pids", "pids.max"))?;
+    Ok((current, limit))
+}
+
+/// Count processes of the cgroup, one per line of cgroup.procs
+fn count_cgroup_processes(cgroup: &Cgroup) -> Result<u64, Box<dyn std::error::Error>> {
+    let content = std::fs::read_to_string(cgroup.file("pids", "cgroup.procs"))?;
+    Ok(content
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .count() as u64)
+}
+
+/// Count processes and their threads in a process filesystem
+fn count_tasks(proc: &Path) -> Result<(u64, u64), Box<dyn std::error::Error>> {
+    let mut processes = 0;
+    let mut threads = 0;
+    for entry in std::fs::read_dir(proc)? {
+        let entry = entry?;
+        if entry.file_name().to_string_lossy().parse::<u32>().is_err() {
+            continue;
+        }
+        // process could exit while listing
+        let Ok(tasks) = std::fs::read_dir(entry.path().join("task")) else {
+            continue;
+        };
+        processes += 1;
+        threads += tasks.count() as u64;
+    }
+    Ok((processes, threads))
+}
+
+/// Take median of counts and peak of cgroup tasks over measurements
+pub fn aggregate_pids(series: &[&PidsMeasurement]) -> Option<PidsMeasurement> {
+    if series.is_empty() {
+        return None;
+    }
+    Some(PidsMeasurement {
+        processes: median(series.iter().map(|p| p.processes as f64).collect()) as u64,
+        threads: median(series.iter().map(|p| p.threads as f64).collect()) as u64,
+        pids_current: series.iter().filter_map(|p| p.pids_current).max(),
+        pids_limit: series.iter().filter_map(|p| p.pids_limit).min(),
+    })
+}
+
+/// Write pids info to writer
+pub fn collect_pids_info<W: std::fmt::Write>(f: &mut W, cgroup: Option<&Cgroup>) {
+    if let Some(cgroup) = cgroup {
+        match read_cgroups_pids(cgroup) {
+            Ok((_, Some(limit))) => {
+                writeln!(f, "cgroups {}: pids limit {}", cgroup.version, limit).unwrap()
+            }
+            Ok((_, None)) => writeln!(f, "cgroups {}: no pids limit", cgroup.version).unwrap(),
+            Err(_) => writeln!(f, "cgroups {}: no pids controller", cgroup.version).unwrap(),
+        }
+    }
+}
+
+/// Detect process and thread counts
+pub fn collect_pids(cgroup: Option<&Cgroup>) -> Option<PidsMeasurement> {
+    let (pids_current, pids_limit) = match cgroup.map(read_cgroups_pids) {
+        Some(Ok((current, limit))) => (Some(current), limit),
+        _ => (None, None),
+    };
+    // pids.current counts threads, listing /proc is a fallback without pids controller
+    let cgroup_counts = cgroup
+        .zip(pids_current)
+        .and_then(|(cgroup, threads)| Some((count_cgroup_processes(cgroup).ok()?, threads)));
+    let counts = match cgroup_counts {
+        Some(counts) => Ok(counts),
+        None => count_tasks(Path::new(PROC)),
+    };
+    let (processes, threads) = match counts {
+        Ok(counts) => counts,
+        Err(err) => {
+            debug!("Cannot count processes: {}", err);
+            return None;
+        }
+    };
+    Some(PidsMeasurement {
+        processes,
+        threads,
+        pids_current,
+        pids_limit,
+    })
+}
+
+/// Tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_count_tasks() {
+        let root = create_fixture(
+            "pids-proc",
+            &[
+                ("1/task/1/stat", ""),
+                ("20/task/20/stat", ""),
+                ("20/task/21/stat", ""),
+                ("20/task/22/stat", ""),
+                ("self/task/1/stat", ""),
+                ("meminfo", ""),
+            ],
+        );
+        assert_eq!(count_tasks(&root).unwrap(), (2, 4));
+    }
+
+    #[test]
+    fn test_pids_cgroups_v2() {
+        let root = create_fixture(
+            "pids-v2",
+            &[
+                ("proc/cgroup", "0::/\n"),
+                ("mnt/cgroup.controllers", "cpu memory pids\n"),
+                ("mnt/pids.current", "25\n"),
+                ("mnt/pids.max", "100\n"),
+                ("mnt/cgroup.procs", "1\n20\n31\n"),
+            ],
+        );
+        let cgroup = Cgroup::detect_at(&root.join("mnt"), &root.join("proc/cgroup")).unwrap();
+        let pids = collect_pids(Some(&cgroup)).unwrap();
+        assert_eq!((pids.processes, pids.threads), (3, 25));
+        assert_eq!(pids.pids_current, Some(25));
+        assert_eq!(pids.pids_limit, Some(100));
+        assert!((pids.utilization().unwrap() - 0.25).abs() < 0.0001);
+
+        std::fs::write(root.join("mnt/pids.max"), "max\n").unwrap();
+        let pids = collect_pids(Some(&cgroup)).unwrap();
+        assert!(pids.utilization().is_none());
+    }
+}
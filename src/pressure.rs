@@ -0,0 +1,269 @@
+use crate::cgroups::*;
+use crate::metrics::median;
+
+use log::debug;
+use std::path::PathBuf;
+
+/// Resources with pressure stall information
+const RESOURCES: [&str; 3] = ["cpu", "memory", "io"];
+
+/// Pressure stall information of a single resource
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct PressureValues {
+    /// Share of time when some tasks stalled, averaged over 10 seconds
+    pub some_avg10: f64,
+    /// Share of time when all tasks stalled, averaged over 10 seconds
+    pub full_avg10: Option<f64>,
+    /// Time when some tasks stalled, in microseconds
+    pub some_total: u64,
+    /// Time when all tasks stalled, in microseconds
+    pub full_total: Option<u64>,
+}
+
+impl PressureValues {
+    /// Stall counters increment since the previous cumulative value
+    fn delta(&self, prev: &PressureValues) -> PressureValues {
+        PressureValues {
+            some_avg10: self.some_avg10,
+            full_avg10: self.full_avg10,
+            some_total: self.some_total.saturating_sub(prev.some_total),
+            // without a previous full total there is no baseline, like for the first sample
+            full_total: self
+                .full_total
+                .zip(prev.full_total)
+                .map(|(total, prev)| total.saturating_sub(prev)),
+        }
+    }
+}
+
+/// Pressure stall information with stall time since the previous measurement
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct PressureMeasurement {
+    pub cpu: Option<PressureValues>,
+    pub memory: Option<PressureValues>,
+    pub io: Option<PressureValues>,
+}
+
+impl PressureMeasurement {
+    /// Pressure values with a metric name prefix for each resource
+    pub fn resources(&self) -> [(&'static str, Option<&PressureValues>); 3] {
+        [
+            ("CPU", self.cpu.as_ref()),
+            ("Memory", self.memory.as_ref()),
+            ("IO", self.io.as_ref()),
+        ]
+    }
+}
+
+/// Cumulative pressure observed at the previous measurement
+#[derive(Default)]
+pub struct PressureState {
+    last: PressureMeasurement,
+}
+
+/// Parse pressure file content
+///
+/// Content format is:
+/// some avg10=0.12 avg60=0.05 avg300=0.01 total=123456
+/// full avg10=0.00 avg60=0.00 avg300=0.00 total=2345
+fn parse_pressure(content: &str) -> Option<PressureValues> {
+    let mut some = None;
+    let mut full = None;
+    for line in content.lines() {
+        let mut parts = line.split_whitespace();
+        let kind = parts.next();
+        let mut avg10 = None;
+        let mut total = None;
+        for part in parts {
+            match part.split_once('=') {
+                Some(("avg10", value)) => avg10 = value.parse::<f64>().ok(),
+                Some(("total", value)) => total = value.parse::<u64>().ok(),
+                _ => (),
+            }
+        }
+        // percents are converted to ratio
+        let values = avg10
+            .zip(total)
+            .map(|(avg10, total)| (avg10 / 100.0, total));
+        match kind {
+            Some("some") => some = values,
+            Some("full") => full = values,
+            _ => (),
+        }
+    }
+    let (some_avg10, some_total) = some?;
+    Some(PressureValues {
+        some_avg10,
+        full_avg10: full.map(|(avg10, _)| avg10),
+        some_total,
+        full_total: full.map(|(_, total)| total),
+    })
+}
+
+/// Pressure file of the resource, cgroup-local one is preferred
+fn pressure_path(cgroup: Option<&Cgroup>, resource: &str) -> Option<PathBuf> {
+    if let Some(cgroup) = cgroup.filter(|cgroup| cgroup.version == CgroupVersion::V2) {
+        let path = cgroup.file(resource, &format!("{}.pressure", resource));
+        if path.exists() {
+            return Some(path);
+        }
+    }
+    let path = PathBuf::from("/proc/pressure").join(resource);
+    if path.exists() {
+        return Some(path);
+    }
+    None
+}
+
+/// Read cumulative pressure of the resource
+fn read_resource_pressure(cgroup: Option<&Cgroup>, resource: &str) -> Option<PressureValues> {
+    let content = std::fs::read_to_string(pressure_path(cgroup, resource)?).ok()?;
+    parse_pressure(&content)
+}
+
+/// Read cumulative pressure for all resources
+fn read_pressure(cgroup: Option<&Cgroup>) -> PressureMeasurement {
+    PressureMeasurement {
+        cpu: read_resource_pressure(cgroup, "cpu"),
+        memory: read_resource_pressure(cgroup, "memory"),
+        io: read_resource_pressure(cgroup, "io"),
+    }
+}
+
+/// Sum stall time and take median of averages over measurements
+fn aggregate_values(series: &[&PressureValues]) -> Option<PressureValues> {
+    if series.is_empty() {
+        return None;
+    }
+    let full_avg10: Vec<f64> = series.iter().filter_map(|v| v.full_avg10).collect();
+    let full_total: Vec<u64> = series.iter().filter_map(|v| v.full_total).collect();
+    Some(PressureValues {
+        some_avg10: median(series.iter().map(|v| v.some_avg10).collect()),
+        full_avg10: (!full_avg10.is_empty()).then(|| median(full_avg10)),
+        some_total: series.iter().map(|v| v.some_total).sum(),
+        full_total: (!full_total.is_empty()).then(|| full_total.iter().sum()),
+    })
+}
+
+/// Aggregate pressure over measurements
+pub fn aggregate_pressure(series: &[&PressureMeasurement]) -> Option<PressureMeasurement> {
+    if series.is_empty() {
+        return None;
+    }
+    Some(PressureMeasurement {
+        cpu: aggregate_values(
+            &series
+                .iter()
+                .filter_map(|m| m.cpu.as_ref())
+                .collect::<Vec<_>>(),
+        ),
+        memory: aggregate_values(
+            &series
+                .iter()
+                .filter_map(|m| m.memory.as_ref())
+                .collect::<Vec<_>>(),
+        ),
+        io: aggregate_values(
+            &series
+                .iter()
+                .filter_map(|m| m.io.as_ref())
+                .collect::<Vec<_>>(),
+        ),
+    })
+}
+
+/// Write pressure info to writer
+pub fn collect_pressure_info<W: std::fmt::Write>(f: &mut W, cgroup: Option<&Cgroup>) {
+    for resource in RESOURCES {
+        match pressure_path(cgroup, resource) {
+            Some(path) => writeln!(f, "PSI: {} from {}", resource, path.display()).unwrap(),
+            None => writeln!(f, "PSI: {} is not available", resource).unwrap(),
+        }
+    }
+}
+
+/// Detect pressure stall information since the previous measurement
+pub fn collect_pressure(
+    cgroup: Option<&Cgroup>,
+    state: &mut PressureState,
+) -> Option<PressureMeasurement> {
+    let delta = |current: &Option<PressureValues>, prev: &Option<PressureValues>| {
+        Some(current.as_ref()?.delta(prev.as_ref()?))
+    };
+    let pressure = read_pressure(cgroup);
+    let last = std::mem::replace(&mut state.last, pressure.clone());
+    let measurement = PressureMeasurement {
+        cpu: delta(&pressure.cpu, &last.cpu),
+        memory: delta(&pressure.memory, &last.memory),
+        io: delta(&pressure.io, &last.io),
+    };
+    debug!("Got pressure {:?}", measurement);
+    if measurement == PressureMeasurement::default() {
+        return None;
+    }
+    Some(measurement)
+}
+
+/// Remember initial stall counters so the first measurement has a baseline
+pub fn init_pressure(state: &mut PressureState, cgroup: Option<&Cgroup>) {
+    state.last = read_pressure(cgroup);
+}
+
+/// Tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_pressure() {
+        let values = parse_pressure(
+            "some avg10=1.50 avg60=0.05 avg300=0.01 total=1000\n\
+             full avg10=0.50 avg60=0.00 avg300=0.00 total=200\n",
+        )
+        .unwrap();
+        assert!((values.some_avg10 - 0.015).abs() < 0.0001);
+        assert!((values.full_avg10.unwrap() - 0.005).abs() < 0.0001);
+        assert_eq!(values.some_total, 1000);
+        assert_eq!(values.full_total, Some(200));
+
+        // older kernels have no full line for cpu
+        let values = parse_pressure("some avg10=0.00 avg60=0.00 avg300=0.00 total=5\n").unwrap();
+        assert_eq!(values.full_avg10, None);
+        assert!(parse_pressure("").is_none());
+    }
+
+    #[test]
+    fn test_pressure_delta() {
+        let values = |some_total: u64, full_total: Option<u64>| PressureValues {
+            some_avg10: 0.0,
+            full_avg10: None,
+            some_total,
+            full_total,
+        };
+        let delta = values(300, Some(500)).delta(&values(100, Some(200)));
+        assert_eq!((delta.some_total, delta.full_total), (200, Some(300)));
+        // the full line appeared after the previous sample
+        let delta = values(300, Some(500)).delta(&values(100, None));
+        assert_eq!((delta.some_total, delta.full_total), (200, None));
+    }
+
+    #[test]
+    fn test_aggregate_pressure() {
+        let sample = |avg10: f64, total: u64| PressureMeasurement {
+            cpu: Some(PressureValues {
+                some_avg10: avg10,
+                full_avg10: None,
+                some_total: total,
+                full_total: None,
+            }),
+            ..Default::default()
+        };
+        let series = [sample(0.1, 100), sample(0.3, 300), sample(0.2, 200)];
+        let aggregated = aggregate_pressure(&series.iter().collect::<Vec<_>>()).unwrap();
+        let cpu = aggregated.cpu.unwrap();
+        assert!((cpu.some_avg10 - 0.2).abs() < 0.0001);
+        assert_eq!(cpu.some_total, 600);
+        assert_eq!(cpu.full_total, None);
+        assert!(aggregated.memory.is_none());
+    }
+}
@@ -0,0 +1,425 @@
+use crate::cgroups::read_keyed;
+use crate::config::{ProcessMatcher, ProcessSelector};
+use crate::files::read_open_files_limit;
+use crate::metrics::median;
+
+use log::debug;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+use sysinfo::{Pid, Process, ProcessExt, System, SystemExt};
+
+/// Resource usage of a monitored process group
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ProcessMeasurement {
+    pub label: String,
+    /// Number of matched processes
+    pub instances: u64,
+    /// CPU usage as a share of all host CPUs
+    pub cpu_utilization: f64,
+    /// Resident memory in bytes
+    pub rss: u64,
+    /// Virtual memory in bytes
+    pub virtual_memory: u64,
+    pub threads: u64,
+    pub open_files: u64,
+    /// Highest share of the open files limit used by a process, None if unknown
+    pub fd_utilization: Option<f64>,
+    /// Time since the most recently started process, in seconds
+    pub uptime: u64,
+    /// Context switches since the previous measurement
+    pub voluntary_switches: u64,
+    pub involuntary_switches: u64,
+    /// Time since the previous measurement
+    pub elapsed: Duration,
+}
+
+impl ProcessMeasurement {
+    /// Convert a counter to a per-second rate
+    pub fn rate(&self, value: u64) -> f64 {
+        let seconds = self.elapsed.as_secs_f64();
+        if seconds > 0.0 {
+            value as f64 / seconds
+        } else {
+            0.0
+        }
+    }
+}
+
+/// Context switches of each process of a group observed at a measurement
+type GroupSwitches = HashMap<Pid, (u64, u64)>;
+
+/// Context switches of process groups observed at the previous measurement
+#[derive(Default)]
+pub struct ProcessState {
+    last_switches: HashMap<String, (GroupSwitches, Instant)>,
+}
+
+/// Resource usage of all processes with the same name
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ProcessUsage {
+    pub name: String,
+    /// CPU usage as a share of all host CPUs
+    pub cpu_utilization: f64,
+    /// Resident memory in bytes
+    pub rss: u64,
+}
+
+/// Heaviest processes by CPU and by memory
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct TopProcesses {
+    /// Number of processes in each list
+    pub count: usize,
+    /// Usage of every process name the lists are selected from
+    pub usages: Vec<ProcessUsage>,
+    pub by_cpu: Vec<ProcessUsage>,
+    pub by_rss: Vec<ProcessUsage>,
+}
+
+impl TopProcesses {
+    /// Select heaviest processes from usage of process names
+    fn select(count: usize, usages: Vec<ProcessUsage>) -> TopProcesses {
+        let mut by_cpu = usages.clone();
+        by_cpu.sort_by(|a, b| b.cpu_utilization.total_cmp(&a.cpu_utilization));
+        by_cpu.truncate(count);
+        let mut by_rss = usages.clone();
+        by_rss.sort_by_key(|usage| std::cmp::Reverse(usage.rss));
+        by_rss.truncate(count);
+        TopProcesses {
+            count,
+            usages,
+            by_cpu,
+            by_rss,
+        }
+    }
+}
+
+/// Count entries of a per-process directory like task or fd
+fn count_entries(path: &Path) -> u64 {
+    std::fs::read_dir(path)
+        .map(|entries| entries.count() as u64)
+        .unwrap_or(0)
+}
+
+/// Read voluntary and involuntary context switches of a process
+fn read_context_switches(proc_dir: &Path) -> (u64, u64) {
+    // status lines look like `voluntary_ctxt_switches:\t150`
+    match read_keyed(&proc_dir.join("status")) {
+        Ok(status) => (
+            status.get("voluntary_ctxt_switches:").copied().unwrap_or(0),
+            status
+                .get("nonvoluntary_ctxt_switches:")
+                .copied()
+                .unwrap_or(0),
+        ),
+        Err(_) => (0, 0),
+    }
+}
+
+/// Sum context switches of processes seen at both measurements, so lifetime
+/// counters of processes joining the group do not appear as a spike
+fn switches_delta(switches: &GroupSwitches, prev_switches: &GroupSwitches) -> (u64, u64) {
+    switches
+        .iter()
+        .filter_map(|(pid, (voluntary, involuntary))| {
+            let (prev_voluntary, prev_involuntary) = prev_switches.get(pid)?;
+            Some((
+                voluntary.saturating_sub(*prev_voluntary),
+                involuntary.saturating_sub(*prev_involuntary),
+            ))
+        })
+        .fold((0, 0), |sum, delta| (sum.0 + delta.0, sum.1 + delta.1))
+}
+
+/// Read a process identifier from a pid file
+fn read_pid_file(path: &Path) -> Option<Pid> {
+    let content = std::fs::read_to_string(path).ok()?;
+    let pid = content.trim().parse::<usize>().ok()?;
+    Some(Pid::from(pid))
+}
+
+/// Find processes matching the selector
+fn select_processes<'a>(sys: &'a System, matcher: &ProcessMatcher) -> Vec<&'a Process> {
+    match matcher {
+        ProcessMatcher::Name(name) => sys
+            .processes()
+            .values()
+            .filter(|process| {
+                // process name is truncated by the kernel so executable is checked too
+                process.name() == name
+                    || process.exe().file_name().and_then(|s| s.to_str()) == Some(name)
+            })
+            .collect(),
+        ProcessMatcher::Cmdline(regex) => sys
+            .processes()
+            .values()
+            .filter(|process| regex.is_match(&process.cmd().join(" ")))
+            .collect(),
+        ProcessMatcher::PidFile(path) => read_pid_file(path)
+            .and_then(|pid| sys.process(pid))
+            .into_iter()
+            .collect(),
+    }
+}
+
+/// Take median of usage over measurements for each process group
+pub fn aggregate_processes(series: &[&[ProcessMeasurement]]) -> Vec<ProcessMeasurement> {
+    let mut labels: Vec<&str> = vec![];
+    for process in series.iter().flat_map(|processes| processes.iter()) {
+        if !labels.contains(&process.label.as_str()) {
+            labels.push(&process.label);
+        }
+    }
+    labels
+        .into_iter()
+        .map(|label| {
+            let samples: Vec<&ProcessMeasurement> = series
+                .iter()
+                .flat_map(|processes| processes.iter())
+                .filter(|p| p.label == label)
+                .collect();
+            let median_of =
+                |f: fn(&ProcessMeasurement) -> f64| median(samples.iter().map(|p| f(p)).collect());
+            ProcessMeasurement {
+                label: label.to_string(),
+                instances: median_of(|p| p.instances as f64) as u64,
+                cpu_utilization: median_of(|p| p.cpu_utilization),
+                rss: median_of(|p| p.rss as f64) as u64,
+                virtual_memory: median_of(|p| p.virtual_memory as f64) as u64,
+                threads: median_of(|p| p.threads as f64) as u64,
+                open_files: median_of(|p| p.open_files as f64) as u64,
+                fd_utilization: samples
+                    .iter()
+                    .filter_map(|p| p.fd_utilization)
+                    .reduce(f64::max),
+                uptime: samples[samples.len() - 1].uptime,
+                voluntary_switches: samples.iter().map(|p| p.voluntary_switches).sum(),
+                involuntary_switches: samples.iter().map(|p| p.involuntary_switches).sum(),
+                elapsed: samples.iter().map(|p| p.elapsed).sum(),
+            }
+        })
+        .collect()
+}
+
+/// Take median CPU and peak memory of each process name over measurements
+/// and select the heaviest ones
+pub fn aggregate_top_processes(series: &[&TopProcesses]) -> Option<TopProcesses> {
+    let count = series.iter().map(|top| top.count).max()?;
+    let mut usages: HashMap<&str, (Vec<f64>, u64)> = HashMap::new();
+    for top in series {
+        for usage in &top.usages {
+            let (cpu, rss) = usages.entry(&usage.name).or_default();
+            cpu.push(usage.cpu_utilization);
+            *rss = (*rss).max(usage.rss);
+        }
+    }
+    let usages = usages
+        .into_iter()
+        .map(|(name, (mut cpu, rss))| {
+            // process did not run during other measurements
+            cpu.resize(series.len(), 0.0);
+            ProcessUsage {
+                name: name.to_string(),
+                cpu_utilization: median(cpu),
+                rss,
+            }
+        })
+        .collect();
+    Some(TopProcesses::select(count, usages))
+}
+
+/// Write monitored processes info to writer
+pub fn collect_processes_info<W: std::fmt::Write>(
+    f: &mut W,
+    sys: &System,
+    selectors: &[ProcessSelector],
+) {
+    for selector in selectors {
+        let pids: Vec<String> = select_processes(sys, &selector.matcher)
+            .iter()
+            .map(|process| process.pid().to_string())
+            .collect();
+        writeln!(
+            f,
+            "Process: {} matches pids [{}]",
+            selector.label,
+            pids.join(", ")
+        )
+        .unwrap();
+    }
+}
+
+/// Detect resource usage of monitored process groups
+pub fn collect_processes(
+    sys: &System,
+    selectors: &[ProcessSelector],
+    state: &mut ProcessState,
+) -> Vec<ProcessMeasurement> {
+    let cpu_count = sys.cpus().len().max(1) as f64;
+    let now = Instant::now();
+    selectors
+        .iter()
+        .map(|selector| {
+            let processes = select_processes(sys, &selector.matcher);
+            debug!("Process {} matched {}", selector.label, processes.len());
+            let proc_dir =
+                |process: &Process| PathBuf::from("/proc").join(process.pid().to_string());
+            // float sum of an empty iterator is a negative zero
+            let cpu_usage = processes
+                .iter()
+                .fold(0.0, |sum, p| sum + p.cpu_usage() as f64);
+            let switches: GroupSwitches = processes
+                .iter()
+                .map(|p| (p.pid(), read_context_switches(&proc_dir(p))))
+                .collect();
+            let (voluntary_switches, involuntary_switches, elapsed) = match state
+                .last_switches
+                .insert(selector.label.clone(), (switches.clone(), now))
+            {
+                Some((prev_switches, prev_time)) => {
+                    let (voluntary, involuntary) = switches_delta(&switches, &prev_switches);
+                    (voluntary, involuntary, now.duration_since(prev_time))
+                }
+                None => (0, 0, Duration::ZERO),
+            };
+            let open_files: Vec<u64> = processes
+                .iter()
+                .map(|p| count_entries(&proc_dir(p).join("fd")))
+                .collect();
+            ProcessMeasurement {
+                label: selector.label.clone(),
+                instances: processes.len() as u64,
+                cpu_utilization: cpu_usage / 100.0 / cpu_count,
+                rss: processes.iter().map(|p| p.memory()).sum(),
+                virtual_memory: processes.iter().map(|p| p.virtual_memory()).sum(),
+                threads: processes
+                    .iter()
+                    .map(|p| count_entries(&proc_dir(p).join("task")))
+                    .sum(),
+                open_files: open_files.iter().sum(),
+                fd_utilization: processes
+                    .iter()
+                    .zip(open_files.iter())
+                    .filter_map(|(p, &open_files)| {
+                        let limit = read_open_files_limit(&proc_dir(p)).filter(|&l| l > 0)?;
+                        Some(open_files as f64 / limit as f64)
+                    })
+                    .reduce(f64::max),
+                uptime: processes.iter().map(|p| p.run_time()).min().unwrap_or(0),
+                voluntary_switches,
+                involuntary_switches,
+                elapsed,
+            }
+        })
+        .collect()
+}
+
+/// Detect the heaviest process names by CPU and by memory
+pub fn collect_top_processes(sys: &System, count: usize) -> TopProcesses {
+    let cpu_count = sys.cpus().len().max(1) as f64;
+    let mut usages: HashMap<&str, ProcessUsage> = HashMap::new();
+    for process in sys.processes().values() {
+        let usage = usages
+            .entry(process.name())
+            .or_insert_with(|| ProcessUsage {
+                name: process.name().to_string(),
+                ..Default::default()
+            });
+        usage.cpu_utilization += process.cpu_usage() as f64 / 100.0 / cpu_count;
+        usage.rss += process.memory();
+    }
+    TopProcesses::select(count, usages.into_values().collect())
+}
+
+/// Tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cgroups::create_fixture;
+    use sysinfo::{PidExt, RefreshKind};
+
+    #[test]
+    fn test_collect_own_process() {
+        let sys = System::new_with_specifics(RefreshKind::new().with_processes(Default::default()));
+        let own_pid = std::process::id().to_string();
+        let root = create_fixture("process", &[("app.pid", &own_pid)]);
+        let pid_file = root.join("app.pid");
+        let selectors: Vec<ProcessSelector> = [
+            format!("pidfile:{}", pid_file.display()),
+            "name:nonexistent-process".to_string(),
+        ]
+        .iter()
+        .map(|s| s.parse().unwrap())
+        .collect();
+        let processes = collect_processes(&sys, &selectors, &mut ProcessState::default());
+        assert_eq!(processes[0].instances, 1);
+        assert!(processes[0].rss > 0);
+        assert!(processes[0].threads >= 1);
+        assert!(processes[0].open_files > 0);
+        assert!(processes[0].fd_utilization.unwrap() > 0.0);
+        assert_eq!(processes[1].instances, 0);
+        assert_eq!(
+            read_pid_file(&pid_file).map(|pid| pid.as_u32()),
+            Some(std::process::id())
+        );
+    }
+
+    #[test]
+    fn test_switches_delta() {
+        let prev = HashMap::from([(Pid::from(1), (100, 10)), (Pid::from(2), (50, 5))]);
+        // process 2 exited and process 3 started with its lifetime counters
+        let current = HashMap::from([(Pid::from(1), (130, 12)), (Pid::from(3), (5000, 500))]);
+        assert_eq!(switches_delta(&current, &prev), (30, 2));
+    }
+
+    #[test]
+    fn test_aggregate_processes() {
+        let sample = |label: &str, rss: u64, uptime: u64| ProcessMeasurement {
+            label: label.to_string(),
+            instances: 1,
+            rss,
+            uptime,
+            ..Default::default()
+        };
+        let first = [sample("web", 100, 10), sample("worker", 10, 10)];
+        let second = [sample("web", 300, 20), sample("worker", 30, 20)];
+        let third = [sample("web", 200, 30)];
+        let processes = aggregate_processes(&[&first[..], &second[..], &third[..]]);
+        assert_eq!(processes.len(), 2);
+        assert_eq!(processes[0].rss, 200);
+        assert_eq!(processes[0].uptime, 30);
+        assert_eq!(processes[1].label, "worker");
+        assert_eq!(processes[1].uptime, 20);
+    }
+
+    #[test]
+    fn test_aggregate_top_processes() {
+        let usage = |name: &str, cpu_utilization: f64, rss: u64| ProcessUsage {
+            name: name.to_string(),
+            cpu_utilization,
+            rss,
+        };
+        let sample = |usages: Vec<ProcessUsage>| TopProcesses::select(1, usages);
+        let series = [
+            sample(vec![usage("java", 0.5, 100), usage("nginx", 0.1, 500)]),
+            sample(vec![usage("java", 0.6, 200), usage("nginx", 0.1, 100)]),
+            sample(vec![usage("java", 0.1, 100), usage("nginx", 0.9, 100)]),
+        ];
+        let top = aggregate_top_processes(&series.iter().collect::<Vec<_>>()).unwrap();
+        assert_eq!(top.by_cpu.len(), 1);
+        assert_eq!(top.by_cpu[0].name, "java");
+        assert!((top.by_cpu[0].cpu_utilization - 0.5).abs() < 0.0001);
+        assert_eq!(top.by_rss.len(), 1);
+        assert_eq!(top.by_rss[0].name, "nginx");
+        assert_eq!(top.by_rss[0].rss, 500);
+
+        // a process steadily below short spikes is the heaviest over the period
+        let series = [
+            sample(vec![usage("java", 0.6, 0), usage("steady", 0.5, 0)]),
+            sample(vec![usage("nginx", 0.9, 0), usage("steady", 0.5, 0)]),
+            sample(vec![usage("python", 0.7, 0), usage("steady", 0.5, 0)]),
+        ];
+        let top = aggregate_top_processes(&series.iter().collect::<Vec<_>>()).unwrap();
+        assert_eq!(top.by_cpu[0].name, "steady");
+    }
+}
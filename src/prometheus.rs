@@ -0,0 +1,566 @@
+use crate::custom::{CustomMetrics, MetricKey, Sample};
+
+use hyper::client::HttpConnector;
+use hyper::{Client, Uri};
+use log::{debug, info, warn};
+use regex::Regex;
+use std::collections::{HashMap, HashSet};
+use std::time::{Duration, Instant};
+
+/// How often scrape targets
+const SCRAPE_INTERVAL: Duration = Duration::from_secs(15);
+
+/// Maximum time of a scrape
+const SCRAPE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Metric type from a TYPE comment
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum MetricType {
+    Counter,
+    Gauge,
+    Histogram,
+    Summary,
+    Untyped,
+}
+
+/// Label names with values
+type Labels = Vec<(String, String)>;
+
+/// Sample line of the text exposition format
+#[derive(Debug, Clone, PartialEq)]
+struct PromSample {
+    name: String,
+    labels: Labels,
+    value: f64,
+}
+
+/// Cumulative histogram of a series
+#[derive(Debug, Clone, Default, PartialEq)]
+struct Histogram {
+    /// Upper bounds with cumulative counts
+    buckets: Vec<(f64, f64)>,
+    sum: f64,
+    count: f64,
+}
+
+/// Series of a scrape grouped by published metrics
+#[derive(Debug, Default)]
+struct Scrape {
+    gauges: HashMap<MetricKey, f64>,
+    counters: HashMap<MetricKey, f64>,
+    histograms: HashMap<MetricKey, Histogram>,
+    /// Sum and count of observations
+    summaries: HashMap<MetricKey, (f64, f64)>,
+}
+
+/// Cumulative value of a series at the previous scrape
+#[derive(Debug, Clone)]
+enum Snapshot {
+    Counter(f64),
+    Histogram(Histogram),
+    Summary(f64, f64),
+}
+
+/// Cumulative values of scraped series kept between scrapes
+#[derive(Default)]
+pub struct PrometheusState {
+    last: HashMap<(String, MetricKey), (Snapshot, Instant)>,
+}
+
+/// Parse labels like `{method="get",code="200"}` and return the rest of the line
+fn parse_labels(content: &str) -> Result<(Labels, &str), String> {
+    let mut labels = vec![];
+    let mut rest = content
+        .strip_prefix('{')
+        .ok_or("expected labels")?
+        .trim_start();
+    loop {
+        rest = rest.trim_start_matches([',', ' ']);
+        if let Some(after) = rest.strip_prefix('}') {
+            return Ok((labels, after));
+        }
+        let (name, after) = rest.split_once('=').ok_or("expected label name")?;
+        let mut chars = after
+            .trim_start()
+            .strip_prefix('"')
+            .ok_or("expected label value")?
+            .char_indices();
+        let mut value = String::new();
+        let end = loop {
+            match chars.next() {
+                Some((_, '\\')) => match chars.next() {
+                    Some((_, 'n')) => value.push('\n'),
+                    Some((_, c)) => value.push(c),
+                    None => return Err("unterminated label value".to_string()),
+                },
+                Some((index, '"')) => break index,
+                Some((_, c)) => value.push(c),
+                None => return Err("unterminated label value".to_string()),
+            }
+        };
+        labels.push((name.trim().to_string(), value));
+        rest = &after.trim_start()[end + 2..];
+    }
+}
+
+/// Parse the text exposition format into metric types and samples
+///
+/// Content format is:
+/// # TYPE http_requests_total counter
+/// http_requests_total{method="post",code="200"} 1027 1395066363000
+fn parse_exposition(content: &str) -> (HashMap<String, MetricType>, Vec<PromSample>) {
+    let mut types = HashMap::new();
+    let mut samples = vec![];
+    for line in content.lines().map(str::trim) {
+        if let Some(comment) = line.strip_prefix('#') {
+            let parts: Vec<&str> = comment.split_whitespace().collect();
+            if let ["TYPE", name, kind] = parts[..] {
+                let kind = match kind {
+                    "counter" => MetricType::Counter,
+                    "gauge" => MetricType::Gauge,
+                    "histogram" => MetricType::Histogram,
+                    "summary" => MetricType::Summary,
+                    _ => MetricType::Untyped,
+                };
+                types.insert(name.to_string(), kind);
+            }
+            continue;
+        }
+        if line.is_empty() {
+            continue;
+        }
+        let name_end = line.find(['{', ' ', '\t']).unwrap_or(line.len());
+        let (name, rest) = line.split_at(name_end);
+        let (labels, rest) = if rest.starts_with('{') {
+            match parse_labels(rest) {
+                Ok(parsed) => parsed,
+                Err(err) => {
+                    debug!("Cannot parse labels of {}: {}", name, err);
+                    continue;
+                }
+            }
+        } else {
+            (vec![], rest)
+        };
+        // the optional timestamp is ignored
+        match rest.split_whitespace().next().map(str::parse::<f64>) {
+            Some(Ok(value)) => samples.push(PromSample {
+                name: name.to_string(),
+                labels,
+                value,
+            }),
+            _ => debug!("Cannot parse value of {}", name),
+        }
+    }
+    (types, samples)
+}
+
+/// Find the metric family of a sample name
+fn family<'a>(name: &'a str, types: &HashMap<String, MetricType>) -> (&'a str, MetricType) {
+    if let Some(&kind) = types.get(name) {
+        return (name, kind);
+    }
+    for suffix in ["_bucket", "_sum", "_count", "_total"] {
+        let Some(base) = name.strip_suffix(suffix) else {
+            continue;
+        };
+        let kind = types.get(base).copied();
+        let matched = match suffix {
+            "_bucket" => kind == Some(MetricType::Histogram),
+            "_sum" | "_count" => {
+                matches!(kind, Some(MetricType::Histogram | MetricType::Summary))
+            }
+            _ => kind == Some(MetricType::Counter),
+        };
+        if let (true, Some(kind)) = (matched, kind) {
+            return (base, kind);
+        }
+    }
+    (name, MetricType::Untyped)
+}
+
+/// Guess a CloudWatch unit from the metric name suffix
+fn unit_of(name: &str, rate: bool) -> &'static str {
+    let name = name.strip_suffix("_total").unwrap_or(name);
+    match (name.rsplit('_').next(), rate) {
+        (Some("seconds"), false) => "Seconds",
+        (Some("bytes"), false) => "Bytes",
+        (Some("bytes"), true) => "Bytes/Second",
+        (_, true) => "Count/Second",
+        _ => "None",
+    }
+}
+
+/// Group allowed samples by published metrics, series differing only
+/// in dropped labels are summed
+fn group_samples(
+    types: &HashMap<String, MetricType>,
+    samples: Vec<PromSample>,
+    metrics: &[Regex],
+    labels: &[String],
+) -> Scrape {
+    let mut scrape = Scrape::default();
+    for sample in samples {
+        let (base, kind) = family(&sample.name, types);
+        if !metrics.iter().any(|regex| regex.is_match(base)) {
+            continue;
+        }
+        let dimensions: Vec<(String, String)> = sample
+            .labels
+            .iter()
+            // bucket bounds and quantiles are not dimensions, empty labels are absent
+            .filter(|(name, value)| {
+                name != "le" && name != "quantile" && labels.contains(name) && !value.is_empty()
+            })
+            .cloned()
+            .collect();
+        // the sample name is the longest of metric names of a family
+        if let Err(err) = MetricKey::new(&sample.name, dimensions.clone(), "None").validate() {
+            debug!("Skipping Prometheus sample: {}", err);
+            continue;
+        }
+        match kind {
+            MetricType::Counter => {
+                let key = MetricKey::new(&sample.name, dimensions, unit_of(&sample.name, true));
+                *scrape.counters.entry(key).or_default() += sample.value;
+            }
+            MetricType::Histogram => {
+                let key = MetricKey::new(base, dimensions, unit_of(base, false));
+                let histogram = scrape.histograms.entry(key).or_default();
+                if sample.name.ends_with("_sum") {
+                    histogram.sum += sample.value;
+                } else if sample.name.ends_with("_count") {
+                    histogram.count += sample.value;
+                } else if let Some(le) = sample
+                    .labels
+                    .iter()
+                    .find(|(name, _)| name == "le")
+                    .and_then(|(_, le)| le.parse::<f64>().ok())
+                {
+                    match histogram.buckets.iter_mut().find(|(bound, _)| *bound == le) {
+                        Some((_, count)) => *count += sample.value,
+                        None => histogram.buckets.push((le, sample.value)),
+                    }
+                }
+            }
+            MetricType::Summary => {
+                // quantiles cannot be aggregated, only sum and count are used
+                let key = MetricKey::new(base, dimensions, unit_of(base, false));
+                let (sum, count) = scrape.summaries.entry(key).or_default();
+                if sample.name.ends_with("_sum") {
+                    *sum += sample.value;
+                } else if sample.name.ends_with("_count") {
+                    *count += sample.value;
+                }
+            }
+            MetricType::Gauge | MetricType::Untyped => {
+                if sample.value.is_finite() {
+                    let key =
+                        MetricKey::new(&sample.name, dimensions, unit_of(&sample.name, false));
+                    *scrape.gauges.entry(key).or_default() += sample.value;
+                }
+            }
+        }
+    }
+    for histogram in scrape.histograms.values_mut() {
+        histogram.buckets.sort_by(|(a, _), (b, _)| a.total_cmp(b));
+    }
+    scrape
+}
+
+/// Estimate statistics of observations between two histogram snapshots
+///
+/// Minimum and maximum are taken from bounds of the lowest and the highest
+/// non-empty buckets
+fn histogram_statistics(current: &Histogram, prev: &Histogram) -> Option<Sample> {
+    let count = current.count - prev.count;
+    let sum = current.sum - prev.sum;
+    // no observations or a counter reset
+    if count <= 0.0 {
+        return None;
+    }
+    let mean = sum / count;
+    let deltas: Vec<(f64, f64)> = current
+        .buckets
+        .iter()
+        .map(|&(le, value)| {
+            let prev_value = prev
+                .buckets
+                .iter()
+                .find(|(bound, _)| *bound == le)
+                .map_or(0.0, |(_, value)| *value);
+            (le, value - prev_value)
+        })
+        .collect();
+    let min = deltas
+        .iter()
+        .find(|(_, delta)| *delta > 0.0)
+        .map_or(mean, |(le, _)| le.min(mean));
+    // the +Inf bucket is bounded by the largest finite bound
+    let max = deltas
+        .iter()
+        .position(|(_, delta)| *delta >= count)
+        .map(|index| match deltas[index].0 {
+            le if le.is_finite() => le,
+            _ if index > 0 => deltas[index - 1].0,
+            _ => mean,
+        })
+        .map_or(mean, |max| max.max(mean));
+    Some(Sample::Statistics {
+        min,
+        max,
+        sum,
+        count,
+    })
+}
+
+/// Convert cumulative values to rates and statistics since the previous scrape
+fn record_scrape(
+    target: &str,
+    scrape: Scrape,
+    now: Instant,
+    state: &mut PrometheusState,
+    custom: &CustomMetrics,
+) {
+    for (key, value) in scrape.gauges {
+        custom.record(key, Sample::Gauge(value));
+    }
+    let mut snapshots: Vec<(MetricKey, Snapshot)> = vec![];
+    snapshots.extend(
+        scrape
+            .counters
+            .into_iter()
+            .map(|(key, value)| (key, Snapshot::Counter(value))),
+    );
+    snapshots.extend(
+        scrape
+            .histograms
+            .into_iter()
+            .map(|(key, histogram)| (key, Snapshot::Histogram(histogram))),
+    );
+    snapshots.extend(
+        scrape
+            .summaries
+            .into_iter()
+            .map(|(key, (sum, count))| (key, Snapshot::Summary(sum, count))),
+    );
+
+    let seen: HashSet<MetricKey> = snapshots.iter().map(|(key, _)| key.clone()).collect();
+    for (key, snapshot) in snapshots {
+        let Some((prev, prev_time)) = state
+            .last
+            .insert((target.to_string(), key.clone()), (snapshot.clone(), now))
+        else {
+            continue;
+        };
+        let seconds = now.duration_since(prev_time).as_secs_f64();
+        let sample = match (&snapshot, &prev) {
+            // a decreased counter was reset
+            (Snapshot::Counter(value), Snapshot::Counter(prev))
+                if value >= prev && seconds > 0.0 =>
+            {
+                Some(Sample::Distribution {
+                    value: (value - prev) / seconds,
+                    weight: 1.0,
+                })
+            }
+            (Snapshot::Histogram(histogram), Snapshot::Histogram(prev)) => {
+                histogram_statistics(histogram, prev)
+            }
+            (Snapshot::Summary(sum, count), Snapshot::Summary(prev_sum, prev_count))
+                if count > prev_count =>
+            {
+                let count = count - prev_count;
+                Some(Sample::Distribution {
+                    value: (sum - prev_sum) / count,
+                    weight: count,
+                })
+            }
+            _ => None,
+        };
+        if let Some(sample) = sample {
+            custom.record(key, sample);
+        }
+    }
+    // forget series which disappeared from the target
+    state
+        .last
+        .retain(|(last_target, key), _| last_target != target || seen.contains(key));
+}
+
+/// Fetch metrics of a target
+async fn scrape(
+    client: &Client<HttpConnector>,
+    target: &Uri,
+) -> Result<String, Box<dyn std::error::Error + Send + Sync>> {
+    let response = tokio::time::timeout(SCRAPE_TIMEOUT, client.get(target.clone())).await??;
+    if !response.status().is_success() {
+        return Err(format!("unexpected status {}", response.status()).into());
+    }
+    let body =
+        tokio::time::timeout(SCRAPE_TIMEOUT, hyper::body::to_bytes(response.into_body())).await??;
+    Ok(String::from_utf8_lossy(&body).into_owned())
+}
+
+/// Task for scraping Prometheus targets periodically
+pub async fn prometheus_scraper(
+    targets: Vec<Uri>,
+    metrics: Vec<Regex>,
+    labels: Vec<String>,
+    custom: CustomMetrics,
+) {
+    let client = Client::new();
+    let mut state = PrometheusState::default();
+    let mut interval = tokio::time::interval(SCRAPE_INTERVAL);
+    interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    info!("Scraping {} Prometheus targets", targets.len());
+    loop {
+        interval.tick().await;
+        for target in &targets {
+            match scrape(&client, target).await {
+                Ok(content) => {
+                    let (types, samples) = parse_exposition(&content);
+                    debug!("Scraped {} samples from {}", samples.len(), target);
+                    let scrape = group_samples(&types, samples, &metrics, &labels);
+                    record_scrape(
+                        &target.to_string(),
+                        scrape,
+                        Instant::now(),
+                        &mut state,
+                        &custom,
+                    );
+                }
+                Err(err) => warn!("Cannot scrape {}: {}", target, err),
+            }
+        }
+    }
+}
+
+/// Tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::custom::CustomValue;
+
+    const EXPOSITION: &str = r#"
+# HELP http_requests_total The total number of HTTP requests.
+# TYPE http_requests_total counter
+http_requests_total{method="post",code="200"} 1027 1395066363000
+http_requests_total{method="post",code="400"} 3 1395066363000
+# TYPE queue_size gauge
+queue_size{queue="a \"quoted\" name"} 5
+# TYPE request_duration_seconds histogram
+request_duration_seconds_bucket{le="0.1"} 10
+request_duration_seconds_bucket{le="0.5"} 15
+request_duration_seconds_bucket{le="+Inf"} 16
+request_duration_seconds_sum 3.5
+request_duration_seconds_count 16
+# TYPE rpc_duration_seconds summary
+rpc_duration_seconds{quantile="0.5"} 0.05
+rpc_duration_seconds_sum 8
+rpc_duration_seconds_count 100
+go_goroutines 12
+"#;
+
+    #[test]
+    fn test_parse_exposition() {
+        let (types, samples) = parse_exposition(EXPOSITION);
+        assert_eq!(types.len(), 4);
+        assert_eq!(samples.len(), 12);
+        assert_eq!(
+            samples[2].labels,
+            vec![("queue".to_string(), "a \"quoted\" name".to_string())]
+        );
+        assert_eq!(samples[5].labels[0].1.parse::<f64>(), Ok(f64::INFINITY));
+        assert_eq!(
+            family("request_duration_seconds_bucket", &types),
+            ("request_duration_seconds", MetricType::Histogram)
+        );
+        assert_eq!(family("go_goroutines", &types).1, MetricType::Untyped);
+
+        let allowed = [Regex::new("^(http|request)_").unwrap()];
+        let scrape = group_samples(&types, samples, &allowed, &["method".to_string()]);
+        assert!(scrape.gauges.is_empty());
+        // series with different codes are summed
+        let key = MetricKey::new(
+            "http_requests_total",
+            vec![("method".to_string(), "post".to_string())],
+            "Count/Second",
+        );
+        assert_eq!(scrape.counters.get(&key), Some(&1030.0));
+        let key = MetricKey::new("request_duration_seconds", vec![], "Seconds");
+        assert_eq!(scrape.histograms[&key].buckets.len(), 3);
+
+        // nothing is published without allowed metrics
+        let (types, samples) = parse_exposition(EXPOSITION);
+        let scrape = group_samples(&types, samples, &[], &[]);
+        assert!(scrape.counters.is_empty() && scrape.histograms.is_empty());
+        // labels CloudWatch would reject skip the series
+        let content = format!("go_goroutines{{pod=\"{}\"}} 1\n", "a".repeat(1025));
+        let (types, samples) = parse_exposition(&content);
+        let scrape = group_samples(&types, samples, &allowed, &["pod".to_string()]);
+        assert!(scrape.gauges.is_empty());
+    }
+
+    #[test]
+    fn test_record_scrape() {
+        let custom = CustomMetrics::default();
+        let mut state = PrometheusState::default();
+        let start = Instant::now();
+        let all = [Regex::new("").unwrap()];
+        let (types, samples) = parse_exposition(EXPOSITION);
+        let scrape = group_samples(&types, samples, &all, &[]);
+        record_scrape("target", scrape, start, &mut state, &custom);
+        // only gauges are known after the first scrape
+        assert_eq!(custom.drain().len(), 2);
+
+        let next = EXPOSITION
+            .replace("} 1027", "} 1057")
+            .replace("le=\"0.1\"} 10", "le=\"0.1\"} 12")
+            .replace("le=\"0.5\"} 15", "le=\"0.5\"} 18")
+            .replace("le=\"+Inf\"} 16", "le=\"+Inf\"} 20")
+            .replace("seconds_sum 3.5", "seconds_sum 4.7")
+            .replace("seconds_count 16", "seconds_count 20");
+        let (types, samples) = parse_exposition(&next);
+        let scrape = group_samples(&types, samples, &all, &[]);
+        record_scrape(
+            "target",
+            scrape,
+            start + Duration::from_secs(10),
+            &mut state,
+            &custom,
+        );
+        let metrics = custom.drain();
+        let value = |name: &str| {
+            metrics
+                .iter()
+                .find(|m| m.key.name == name)
+                .map(|m| m.value.clone())
+        };
+        assert_eq!(
+            value("http_requests_total"),
+            Some(CustomValue::Statistics {
+                min: 3.0,
+                max: 3.0,
+                sum: 3.0,
+                count: 1.0
+            })
+        );
+        // four observations with mean 0.3 in buckets up to 0.1, 0.5 and above
+        let Some(CustomValue::Statistics {
+            min,
+            max,
+            sum,
+            count,
+        }) = value("request_duration_seconds")
+        else {
+            panic!("no histogram statistics");
+        };
+        assert!((min - 0.1).abs() < 0.0001);
+        assert!((max - 0.5).abs() < 0.0001);
+        assert!((sum - 1.2).abs() < 0.0001);
+        assert_eq!(count, 4.0);
+        // summary did not change
+        assert_eq!(value("rpc_duration_seconds"), None);
+    }
+}
@@ -0,0 +1,197 @@
+use crate::metrics::median;
+
+use log::debug;
+
+/// Socket usage of the current network namespace
+const PROC_NET_SOCKSTAT: &str = "/proc/net/sockstat";
+
+/// TCP sockets of the current network namespace
+const PROC_NET_TCP: [&str; 2] = ["/proc/net/tcp", "/proc/net/tcp6"];
+
+/// TCP states as encoded in /proc/net/tcp
+const TCP_ESTABLISHED: u8 = 0x01;
+const TCP_SYN_SENT: u8 = 0x02;
+const TCP_TIME_WAIT: u8 = 0x06;
+const TCP_CLOSE_WAIT: u8 = 0x08;
+
+/// TCP connections by state and socket usage
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SocketsMeasurement {
+    pub established: u64,
+    pub syn_sent: u64,
+    pub time_wait: u64,
+    pub close_wait: u64,
+    /// Sockets in use of all protocols, None if sockstat is not available
+    pub used: Option<u64>,
+    /// TCP sockets not attached to any process
+    pub orphan: Option<u64>,
+    /// Memory used by TCP sockets in bytes
+    pub tcp_memory: Option<u64>,
+}
+
+/// Parse sockstat content into used sockets, orphans and TCP memory in pages
+///
+/// Content format is:
+/// sockets: used 16
+/// TCP: inuse 4 orphan 0 tw 0 alloc 4 mem 0
+fn parse_sockstat(content: &str) -> (Option<u64>, Option<u64>, Option<u64>) {
+    let mut used = None;
+    let mut orphan = None;
+    let mut mem = None;
+    for line in content.lines() {
+        let Some((protocol, values)) = line.split_once(':') else {
+            continue;
+        };
+        let parts: Vec<&str> = values.split_whitespace().collect();
+        for pair in parts.chunks(2) {
+            let [key, value] = pair else { continue };
+            let value = value.parse::<u64>().ok();
+            match (protocol, *key) {
+                ("sockets", "used") => used = value,
+                ("TCP", "orphan") => orphan = value,
+                ("TCP", "mem") => mem = value,
+                _ => (),
+            }
+        }
+    }
+    (used, orphan, mem)
+}
+
+/// Count TCP connections by state in /proc/net/tcp content
+///
+/// Line format is:
+/// 0: 0100007F:BC8F 00000000:0000 0A 00000000:00000000 ...
+fn count_tcp_states(content: &str, ports: &[u16], measurement: &mut SocketsMeasurement) {
+    for line in content.lines().skip(1) {
+        let mut parts = line.split_whitespace().skip(1);
+        let (Some(local), Some(_), Some(state)) = (parts.next(), parts.next(), parts.next()) else {
+            continue;
+        };
+        if !ports.is_empty() {
+            let port = local
+                .rsplit_once(':')
+                .and_then(|(_, port)| u16::from_str_radix(port, 16).ok());
+            if !port.is_some_and(|port| ports.contains(&port)) {
+                continue;
+            }
+        }
+        match u8::from_str_radix(state, 16) {
+            Ok(TCP_ESTABLISHED) => measurement.established += 1,
+            Ok(TCP_SYN_SENT) => measurement.syn_sent += 1,
+            Ok(TCP_TIME_WAIT) => measurement.time_wait += 1,
+            Ok(TCP_CLOSE_WAIT) => measurement.close_wait += 1,
+            _ => (),
+        }
+    }
+}
+
+/// Size of a memory page in bytes
+fn page_size() -> u64 {
+    // SAFETY: sysconf has no side effects
+    let size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) };
+    if size > 0 {
+        size as u64
+    } else {
+        4096
+    }
+}
+
+/// Take median of connection counts over measurements
+pub fn aggregate_sockets(series: &[&SocketsMeasurement]) -> Option<SocketsMeasurement> {
+    if series.is_empty() {
+        return None;
+    }
+    let median_of = |f: fn(&SocketsMeasurement) -> u64| {
+        median(series.iter().map(|s| f(s) as f64).collect()) as u64
+    };
+    let median_of_known = |f: fn(&SocketsMeasurement) -> Option<u64>| {
+        let values: Vec<f64> = series
+            .iter()
+            .filter_map(|s| f(s))
+            .map(|v| v as f64)
+            .collect();
+        (!values.is_empty()).then(|| median(values) as u64)
+    };
+    Some(SocketsMeasurement {
+        established: median_of(|s| s.established),
+        syn_sent: median_of(|s| s.syn_sent),
+        time_wait: median_of(|s| s.time_wait),
+        close_wait: median_of(|s| s.close_wait),
+        used: median_of_known(|s| s.used),
+        orphan: median_of_known(|s| s.orphan),
+        tcp_memory: median_of_known(|s| s.tcp_memory),
+    })
+}
+
+/// Write sockets info to writer
+pub fn collect_sockets_info<W: std::fmt::Write>(f: &mut W, ports: &[u16]) {
+    if ports.is_empty() {
+        writeln!(f, "Sockets: TCP connections on all ports").unwrap();
+    } else {
+        let ports: Vec<String> = ports.iter().map(|port| port.to_string()).collect();
+        writeln!(f, "Sockets: TCP connections on ports {}", ports.join(", ")).unwrap();
+    }
+}
+
+/// Detect TCP connection states and socket usage
+pub fn collect_sockets(ports: &[u16]) -> Option<SocketsMeasurement> {
+    let mut measurement = SocketsMeasurement::default();
+    let mut found = false;
+    for path in PROC_NET_TCP {
+        match std::fs::read_to_string(path) {
+            Ok(content) => {
+                count_tcp_states(&content, ports, &mut measurement);
+                found = true;
+            }
+            // tcp6 is absent if IPv6 is disabled
+            Err(err) => debug!("Cannot read {}: {}", path, err),
+        }
+    }
+    if let Ok(content) = std::fs::read_to_string(PROC_NET_SOCKSTAT) {
+        let (used, orphan, mem) = parse_sockstat(&content);
+        measurement.used = used;
+        measurement.orphan = orphan;
+        measurement.tcp_memory = mem.map(|pages| pages * page_size());
+        found = true;
+    }
+    found.then_some(measurement)
+}
+
+/// Tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_sockstat() {
+        let content = "\
+sockets: used 16
+TCP: inuse 4 orphan 2 tw 0 alloc 4 mem 3
+UDP: inuse 0 mem 7
+";
+        assert_eq!(parse_sockstat(content), (Some(16), Some(2), Some(3)));
+        assert_eq!(parse_sockstat(""), (None, None, None));
+    }
+
+    #[test]
+    fn test_count_tcp_states() {
+        let content = "  sl  local_address rem_address   st tx_queue rx_queue
+   0: 0100007F:1F90 00000000:0000 0A 00000000:00000000 00:00000000 00000000 0 0 1 1
+   1: 0100007F:1F90 0100007F:C9FA 01 00000000:00000000 00:00000000 00000000 0 0 2 1
+   2: 0100007F:1F90 0100007F:C9FB 08 00000000:00000000 00:00000000 00000000 0 0 3 1
+   3: 0100007F:C9FA 0100007F:1F90 01 00000000:00000000 00:00000000 00000000 0 0 4 1
+   4: 0100007F:C9FC 0100007F:0050 06 00000000:00000000 00:00000000 00000000 0 0 0 1
+";
+        let mut all = SocketsMeasurement::default();
+        count_tcp_states(content, &[], &mut all);
+        assert_eq!(all.established, 2);
+        assert_eq!(all.close_wait, 1);
+        assert_eq!(all.time_wait, 1);
+
+        let mut filtered = SocketsMeasurement::default();
+        count_tcp_states(content, &[8080], &mut filtered);
+        assert_eq!(filtered.established, 1);
+        assert_eq!(filtered.close_wait, 1);
+        assert_eq!(filtered.time_wait, 0);
+    }
+}
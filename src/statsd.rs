@@ -0,0 +1,208 @@
+use crate::custom::{CustomMetrics, MetricKey, Sample};
+
+use log::{debug, error, info};
+use std::net::SocketAddr;
+use std::os::unix::fs::FileTypeExt;
+use std::path::PathBuf;
+use tokio::net::{UdpSocket, UnixDatagram};
+
+/// Maximum size of a datagram
+const MAX_DATAGRAM_SIZE: usize = 65535;
+
+/// Parse a StatsD line with optional DogStatsD sample rate and tags
+///
+/// Line format is:
+/// name:value|type|@sample_rate|#tag1:value1,tag2:value2
+fn parse_statsd_line(line: &str) -> Result<(MetricKey, Sample), String> {
+    let mut sections = line.split('|');
+    let (name, value) = sections
+        .next()
+        .and_then(|section| section.rsplit_once(':'))
+        .filter(|(name, value)| !name.is_empty() && !value.is_empty())
+        .ok_or_else(|| format!("expected name:value in {}", line))?;
+    let kind = sections
+        .next()
+        .ok_or_else(|| format!("no metric type in {}", line))?;
+    let mut sample_rate = 1.0;
+    let mut dimensions = vec![];
+    for section in sections {
+        if let Some(rate) = section.strip_prefix('@') {
+            sample_rate = rate
+                .parse::<f64>()
+                .ok()
+                .filter(|&rate| rate > 0.0 && rate <= 1.0)
+                .ok_or_else(|| format!("bad sample rate in {}", line))?;
+        } else if let Some(tags) = section.strip_prefix('#') {
+            // tags without a value cannot be dimensions
+            dimensions.extend(
+                tags.split(',')
+                    .filter_map(|tag| tag.split_once(':'))
+                    .filter(|(key, value)| !key.is_empty() && !value.is_empty())
+                    .map(|(key, value)| (key.to_string(), value.to_string())),
+            );
+        }
+    }
+    let number = || {
+        value
+            .parse::<f64>()
+            .ok()
+            .filter(|value| value.is_finite())
+            .ok_or_else(|| format!("bad value in {}", line))
+    };
+    let (unit, sample) = match kind {
+        "c" => ("Count", Sample::Counter(number()? / sample_rate)),
+        // signed gauge values change the current value
+        "g" if value.starts_with(['+', '-']) => ("None", Sample::GaugeDelta(number()?)),
+        "g" => ("None", Sample::Gauge(number()?)),
+        "ms" | "h" | "d" => (
+            if kind == "ms" { "Milliseconds" } else { "None" },
+            Sample::Distribution {
+                value: number()?,
+                weight: 1.0 / sample_rate,
+            },
+        ),
+        "s" => ("Count", Sample::Set(value.to_string())),
+        _ => return Err(format!("unknown metric type {}", kind)),
+    };
+    let key = MetricKey::new(name, dimensions, unit);
+    key.validate()?;
+    Ok((key, sample))
+}
+
+/// Record all metrics of a datagram
+fn handle_datagram(datagram: &[u8], custom: &CustomMetrics) {
+    let content = String::from_utf8_lossy(datagram);
+    for line in content.lines() {
+        let line = line.trim();
+        // skip empty lines and DogStatsD events and service checks
+        if line.is_empty() || line.starts_with("_e{") || line.starts_with("_sc|") {
+            continue;
+        }
+        match parse_statsd_line(line) {
+            Ok((key, sample)) => custom.record(key, sample),
+            Err(err) => debug!("Cannot parse StatsD line: {}", err),
+        }
+    }
+}
+
+/// Receive StatsD datagrams from a UDP socket
+async fn receive_udp(socket: UdpSocket, custom: CustomMetrics) {
+    let mut buf = vec![0; MAX_DATAGRAM_SIZE];
+    loop {
+        match socket.recv(&mut buf).await {
+            Ok(len) => handle_datagram(&buf[..len], &custom),
+            Err(err) => error!("Cannot receive StatsD datagram: {}", err),
+        }
+    }
+}
+
+/// Receive StatsD datagrams from a Unix datagram socket
+async fn receive_unix(socket: UnixDatagram, custom: CustomMetrics) {
+    let mut buf = vec![0; MAX_DATAGRAM_SIZE];
+    loop {
+        match socket.recv(&mut buf).await {
+            Ok(len) => handle_datagram(&buf[..len], &custom),
+            Err(err) => error!("Cannot receive StatsD datagram: {}", err),
+        }
+    }
+}
+
+/// Task for receiving StatsD metrics on a UDP address
+pub async fn statsd_udp_listener(address: SocketAddr, custom: CustomMetrics) {
+    match UdpSocket::bind(address).await {
+        Ok(socket) => {
+            info!("Listening for StatsD metrics on udp {}", address);
+            receive_udp(socket, custom).await;
+        }
+        Err(err) => error!("Cannot listen for StatsD metrics on {}: {}", address, err),
+    }
+}
+
+/// Task for receiving StatsD metrics on a Unix datagram socket
+pub async fn statsd_unix_listener(path: PathBuf, custom: CustomMetrics) {
+    // a socket file left by a previous run prevents binding
+    if std::fs::metadata(&path).is_ok_and(|meta| meta.file_type().is_socket()) {
+        let _ = std::fs::remove_file(&path);
+    }
+    match UnixDatagram::bind(&path) {
+        Ok(socket) => {
+            info!("Listening for StatsD metrics on unix {}", path.display());
+            receive_unix(socket, custom).await;
+        }
+        Err(err) => error!(
+            "Cannot listen for StatsD metrics on {}: {}",
+            path.display(),
+            err
+        ),
+    }
+}
+
+/// Tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::custom::CustomValue;
+
+    #[test]
+    fn test_parse_statsd_line() {
+        let (key, sample) = parse_statsd_line("requests:2|c|@0.5|#route:api,canary").unwrap();
+        assert_eq!(key.name, "requests");
+        assert_eq!(key.unit, "Count");
+        assert_eq!(
+            key.dimensions,
+            vec![("route".to_string(), "api".to_string())]
+        );
+        assert_eq!(sample, Sample::Counter(4.0));
+
+        let (key, sample) = parse_statsd_line("latency:12.5|ms").unwrap();
+        assert_eq!(key.unit, "Milliseconds");
+        assert_eq!(
+            sample,
+            Sample::Distribution {
+                value: 12.5,
+                weight: 1.0
+            }
+        );
+        assert_eq!(
+            parse_statsd_line("queue:-3|g").unwrap().1,
+            Sample::GaugeDelta(-3.0)
+        );
+        assert_eq!(
+            parse_statsd_line("users:alice|s").unwrap().1,
+            Sample::Set("alice".to_string())
+        );
+        assert!(parse_statsd_line("requests:1").is_err());
+        assert!(parse_statsd_line("requests:x|c").is_err());
+        assert!(parse_statsd_line("requests:1|q").is_err());
+
+        // keys CloudWatch would reject
+        let tags: Vec<String> = (0..30).map(|i| format!("tag{}:value", i)).collect();
+        let line = format!("requests:1|c|#{}", tags.join(","));
+        assert!(parse_statsd_line(&line).is_err());
+        let line = format!("requests:1|c|#route:{}", "a".repeat(1025));
+        assert!(parse_statsd_line(&line).is_err());
+        assert!(parse_statsd_line("requests:1|c|#ServiceName:api").is_err());
+        assert!(parse_statsd_line("requests:1|c|#route:a,route:b").is_err());
+        assert!(parse_statsd_line("requests:1|c|# :api").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_statsd_udp_listener() {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let address = socket.local_addr().unwrap();
+        let custom = CustomMetrics::default();
+        let task = tokio::spawn(receive_udp(socket, custom.clone()));
+
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        client
+            .send_to(b"requests:1|c\nrequests:2|c\n_sc|check|0\nbroken", address)
+            .await
+            .unwrap();
+        tokio::time::sleep(std::time::Duration::from_millis(200)).await;
+        task.abort();
+
+        let metrics = custom.drain();
+        assert_eq!(metrics.len(), 1);
+        assert_eq!(metrics[0].value, CustomValue::Value(3.0));
+    }
+}
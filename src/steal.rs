@@ -0,0 +1,133 @@
+use crate::cpu::{read_cpu_times, CpuTimes};
+
+use log::{info, warn};
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+/// CPU steal time over a sliding window of samples
+pub struct StealState {
+    window: Duration,
+    threshold: f64,
+    /// Sample time with steal and total CPU ticks
+    samples: VecDeque<(Instant, u64, u64)>,
+    sustained: bool,
+}
+
+impl StealState {
+    pub fn new(window: Duration, threshold: f64) -> StealState {
+        StealState {
+            window,
+            threshold,
+            samples: VecDeque::new(),
+            sustained: false,
+        }
+    }
+
+    /// Share of CPU time stolen over the window, None until the window is filled
+    fn window_ratio(&self, now: Instant) -> Option<f64> {
+        let (oldest, _, _) = self.samples.front()?;
+        if now.duration_since(*oldest) < self.window {
+            return None;
+        }
+        let steal: u64 = self.samples.iter().map(|(_, steal, _)| steal).sum();
+        let total: u64 = self.samples.iter().map(|(_, _, total)| total).sum();
+        (total > 0).then(|| steal as f64 / total as f64)
+    }
+}
+
+/// Take whether steal was sustained at any time over measurements
+pub fn aggregate_steal_sustained(series: &[bool]) -> Option<bool> {
+    if series.is_empty() {
+        return None;
+    }
+    Some(series.iter().any(|&sustained| sustained))
+}
+
+/// Write steal info to writer
+pub fn collect_steal_info<W: std::fmt::Write>(f: &mut W, state: &StealState) {
+    let since_boot = read_cpu_times().map(|times| times.ratio(times.steal));
+    writeln!(
+        f,
+        "Steal: threshold {:.3} over {} s window, since boot {}",
+        state.threshold,
+        state.window.as_secs(),
+        since_boot.map_or("unknown".to_string(), |ratio| format!("{:.3}", ratio))
+    )
+    .unwrap();
+    if let Some(ratio) = since_boot.filter(|&ratio| ratio > state.threshold) {
+        writeln!(
+            f,
+            "Steal: {:.3} since boot exceeds threshold, the host is contended",
+            ratio
+        )
+        .unwrap();
+    }
+}
+
+/// Add CPU times of a measurement to the window and detect sustained steal
+pub fn track_steal(state: &mut StealState, times: Option<&CpuTimes>) -> Option<bool> {
+    track_steal_at(state, times, Instant::now())
+}
+
+/// Add CPU times of a measurement taken at the given time to the window
+fn track_steal_at(state: &mut StealState, times: Option<&CpuTimes>, now: Instant) -> Option<bool> {
+    if let Some(times) = times {
+        state.samples.push_back((now, times.steal, times.total()));
+    }
+    // keep the newest sample older than the window so the window stays covered
+    while state.samples.len() > 1 && now.duration_since(state.samples[1].0) >= state.window {
+        state.samples.pop_front();
+    }
+    let ratio = state.window_ratio(now)?;
+    let sustained = ratio > state.threshold;
+    if sustained != state.sustained {
+        if sustained {
+            warn!(
+                "CPU steal {:.3} over {} s exceeds threshold {:.3}",
+                ratio,
+                state.window.as_secs(),
+                state.threshold
+            );
+        } else {
+            info!("CPU steal {:.3} is below threshold again", ratio);
+        }
+        state.sustained = sustained;
+    }
+    Some(sustained)
+}
+
+/// Tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_track_steal() {
+        let times = |steal: u64, idle: u64| CpuTimes {
+            steal,
+            idle,
+            ..Default::default()
+        };
+        let mut state = StealState::new(Duration::from_secs(60), 0.1);
+        let start = Instant::now();
+        let at = |seconds: u64| start + Duration::from_secs(seconds);
+        // window is not filled yet
+        assert_eq!(
+            track_steal_at(&mut state, Some(&times(50, 50)), start),
+            None
+        );
+
+        state.samples = VecDeque::from(vec![(start, 0, 100), (at(30), 0, 100), (at(90), 20, 80)]);
+        // the first sample leaves the window
+        assert_eq!(
+            track_steal_at(&mut state, Some(&times(0, 100)), at(120)),
+            Some(false)
+        );
+        assert_eq!(state.samples.len(), 3);
+        assert_eq!(
+            track_steal_at(&mut state, Some(&times(50, 50)), at(121)),
+            Some(true)
+        );
+        assert!(state.sustained);
+    }
+}
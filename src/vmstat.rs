@@ -0,0 +1,209 @@
+use crate::cgroups::*;
+use crate::cpu::PROC_STAT;
+
+use log::debug;
+use std::collections::HashMap;
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+/// Virtual memory statistics of the host
+const PROC_VMSTAT: &str = "/proc/vmstat";
+
+/// Paging and scheduling counters
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct VmstatCounters {
+    pub page_faults: u64,
+    pub major_page_faults: u64,
+    /// Pages swapped in and out
+    pub swap_ins: u64,
+    pub swap_outs: u64,
+    /// Evicted pages read back soon after
+    pub workingset_refaults: u64,
+    pub context_switches: u64,
+}
+
+impl VmstatCounters {
+    /// Counters increment since the previous cumulative value
+    fn delta(&self, prev: &VmstatCounters) -> VmstatCounters {
+        VmstatCounters {
+            page_faults: self.page_faults.saturating_sub(prev.page_faults),
+            major_page_faults: self
+                .major_page_faults
+                .saturating_sub(prev.major_page_faults),
+            swap_ins: self.swap_ins.saturating_sub(prev.swap_ins),
+            swap_outs: self.swap_outs.saturating_sub(prev.swap_outs),
+            workingset_refaults: self
+                .workingset_refaults
+                .saturating_sub(prev.workingset_refaults),
+            context_switches: self.context_switches.saturating_sub(prev.context_switches),
+        }
+    }
+}
+
+/// Paging and scheduling activity during a time interval
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct VmstatMeasurement {
+    pub counters: VmstatCounters,
+    pub elapsed: Duration,
+}
+
+impl VmstatMeasurement {
+    /// Convert a counter to a per-second rate
+    pub fn rate(&self, value: u64) -> f64 {
+        let seconds = self.elapsed.as_secs_f64();
+        if seconds > 0.0 {
+            value as f64 / seconds
+        } else {
+            0.0
+        }
+    }
+}
+
+/// Paging and scheduling counters observed at the previous measurement
+#[derive(Default)]
+pub struct VmstatState {
+    last: Option<(VmstatCounters, Instant)>,
+}
+
+/// Sum of refaults, newer kernels split them into anon and file ones
+fn workingset_refaults(stat: &HashMap<String, u64>, prefix: &str) -> Option<u64> {
+    let key = |name: &str| format!("{}{}", prefix, name);
+    if let Some(&value) = stat.get(&key("workingset_refault")) {
+        return Some(value);
+    }
+    let anon = stat.get(&key("workingset_refault_anon"));
+    let file = stat.get(&key("workingset_refault_file"));
+    if anon.is_none() && file.is_none() {
+        return None;
+    }
+    Some(anon.unwrap_or(&0) + file.unwrap_or(&0))
+}
+
+/// Take host counters from vmstat and override fault counters with cgroup ones
+fn parse_vmstat_counters(
+    vmstat: &HashMap<String, u64>,
+    proc_stat: &HashMap<String, u64>,
+    cgroup_stat: Option<&HashMap<String, u64>>,
+) -> VmstatCounters {
+    let mut counters = VmstatCounters {
+        page_faults: vmstat.get("pgfault").copied().unwrap_or(0),
+        major_page_faults: vmstat.get("pgmajfault").copied().unwrap_or(0),
+        swap_ins: vmstat.get("pswpin").copied().unwrap_or(0),
+        swap_outs: vmstat.get("pswpout").copied().unwrap_or(0),
+        workingset_refaults: workingset_refaults(vmstat, "").unwrap_or(0),
+        context_switches: proc_stat.get("ctxt").copied().unwrap_or(0),
+    };
+    if let Some(stat) = cgroup_stat {
+        // cgroups v1 reports hierarchical counters with a total_ prefix
+        let prefix = if stat.contains_key("total_pgfault") {
+            "total_"
+        } else {
+            ""
+        };
+        let key = |name: &str| format!("{}{}", prefix, name);
+        if let (Some(&faults), Some(&major_faults)) =
+            (stat.get(&key("pgfault")), stat.get(&key("pgmajfault")))
+        {
+            counters.page_faults = faults;
+            counters.major_page_faults = major_faults;
+            if let Some(refaults) = workingset_refaults(stat, prefix) {
+                counters.workingset_refaults = refaults;
+            }
+        }
+    }
+    counters
+}
+
+/// Read cumulative paging and scheduling counters
+fn read_vmstat(cgroup: Option<&Cgroup>) -> Result<VmstatCounters, Box<dyn std::error::Error>> {
+    let vmstat = read_keyed(Path::new(PROC_VMSTAT))?;
+    let proc_stat = read_keyed(Path::new(PROC_STAT))?;
+    let cgroup_stat =
+        cgroup.and_then(|cgroup| read_keyed(&cgroup.file("memory", "memory.stat")).ok());
+    Ok(parse_vmstat_counters(
+        &vmstat,
+        &proc_stat,
+        cgroup_stat.as_ref(),
+    ))
+}
+
+/// Sum paging and scheduling activity over measurements
+pub fn aggregate_vmstat(series: &[&VmstatMeasurement]) -> Option<VmstatMeasurement> {
+    if series.is_empty() {
+        return None;
+    }
+    Some(VmstatMeasurement {
+        counters: VmstatCounters {
+            page_faults: series.iter().map(|v| v.counters.page_faults).sum(),
+            major_page_faults: series.iter().map(|v| v.counters.major_page_faults).sum(),
+            swap_ins: series.iter().map(|v| v.counters.swap_ins).sum(),
+            swap_outs: series.iter().map(|v| v.counters.swap_outs).sum(),
+            workingset_refaults: series.iter().map(|v| v.counters.workingset_refaults).sum(),
+            context_switches: series.iter().map(|v| v.counters.context_switches).sum(),
+        },
+        elapsed: series.iter().map(|v| v.elapsed).sum(),
+    })
+}
+
+/// Detect paging and scheduling activity since the previous measurement
+pub fn collect_vmstat(
+    cgroup: Option<&Cgroup>,
+    state: &mut VmstatState,
+) -> Option<VmstatMeasurement> {
+    let counters = match read_vmstat(cgroup) {
+        Ok(counters) => counters,
+        Err(err) => {
+            debug!("Cannot read vmstat counters: {}", err);
+            return None;
+        }
+    };
+    let now = Instant::now();
+    let (prev_counters, prev_time) = state.last.replace((counters.clone(), now))?;
+    Some(VmstatMeasurement {
+        counters: counters.delta(&prev_counters),
+        elapsed: now.duration_since(prev_time),
+    })
+}
+
+/// Remember initial counters so the first measurement has a baseline
+pub fn init_vmstat(state: &mut VmstatState, cgroup: Option<&Cgroup>) {
+    let _ = collect_vmstat(cgroup, state);
+}
+
+/// Tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_vmstat_counters() {
+        let vmstat = parse_keyed(
+            "pgfault 1000\npgmajfault 10\npswpin 5\npswpout 7\n\
+             workingset_refault_anon 1\nworkingset_refault_file 2\n",
+        );
+        let proc_stat = parse_keyed("cpu  1 2 3 4\nctxt 500\nprocs_running 1\n");
+        let counters = parse_vmstat_counters(&vmstat, &proc_stat, None);
+        assert_eq!(
+            counters,
+            VmstatCounters {
+                page_faults: 1000,
+                major_page_faults: 10,
+                swap_ins: 5,
+                swap_outs: 7,
+                workingset_refaults: 3,
+                context_switches: 500,
+            }
+        );
+
+        // cgroups v1 hierarchical counters take precedence over host ones
+        let cgroup_stat = parse_keyed(
+            "pgfault 1\npgmajfault 1\ntotal_pgfault 100\ntotal_pgmajfault 2\n\
+             total_workingset_refault 4\n",
+        );
+        let counters = parse_vmstat_counters(&vmstat, &proc_stat, Some(&cgroup_stat));
+        assert_eq!(counters.page_faults, 100);
+        assert_eq!(counters.major_page_faults, 2);
+        assert_eq!(counters.workingset_refaults, 4);
+        assert_eq!(counters.swap_ins, 5);
+    }
+}